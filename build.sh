@@ -124,7 +124,7 @@ cp *.kll kll/
 mkdir log
 mv *.log *.h log/
 
-zip -v "${OutFile}" *.kll *.dfu.bin *.json kll/* log/*
+zip -v "${OutFile}" *.kll *.dfu.bin *.json *.svg kll/* log/*
 
 echo -n " >>> Build Completed "
 if [ "$RETVAL" -eq 0 ]; then
@@ -0,0 +1,198 @@
+use crate::kll::Led;
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Most frames a generator may produce for one cycle
+pub const MAX_STEPS: usize = 1000;
+
+fn default_steps() -> usize {
+    50
+}
+
+fn deserialize_steps<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    let steps = usize::deserialize(deserializer)?;
+    if steps > MAX_STEPS {
+        return Err(serde::de::Error::custom(format!(
+            "steps must be at most {}",
+            MAX_STEPS
+        )));
+    }
+    Ok(steps)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+/// Declarative animation, expanded into `Animation.frames` when generating KLL.
+/// Colors are `[r, g, b]`, `steps` is the number of frames of one cycle (at most `MAX_STEPS`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Generator {
+    /// A repeating gradient through `colors` moving across the board
+    Wave {
+        colors: Vec<[u8; 3]>,
+        direction: Direction,
+        #[serde(default = "default_steps", deserialize_with = "deserialize_steps")]
+        steps: usize,
+    },
+    /// The whole board fading in and out
+    Breathe {
+        color: [u8; 3],
+        #[serde(default = "default_steps", deserialize_with = "deserialize_steps")]
+        steps: usize,
+    },
+    /// A single color
+    Solid { color: [u8; 3] },
+    /// A band of color expanding from the middle of the board, or from the LED of `key` (a
+    /// scan code) which then starts the animation when pressed. `speed`, in percent of the
+    /// columns per frame, sets the number of frames instead of `steps`.
+    Ripple {
+        color: [u8; 3],
+        #[serde(default = "default_steps", deserialize_with = "deserialize_steps")]
+        steps: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        speed: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+}
+
+const OFF: [u8; 3] = [0, 0, 0];
+/// Width of the ripple band, in percent of the columns
+const RIPPLE_WIDTH: f32 = 5.;
+
+/// `P[c:10%](r,g,b)` pixel at a percentage of the columns (or rows)
+fn pixel(axis: &str, pos: f32, color: [u8; 3]) -> String {
+    format!(
+        "P[{}:{}%]({},{},{})",
+        axis,
+        pos.round() as i32,
+        color[0],
+        color[1],
+        color[2]
+    )
+}
+
+fn scale(color: [u8; 3], factor: f32) -> [u8; 3] {
+    color.map(|c| (c as f32 * factor).round() as u8)
+}
+
+fn fill(color: [u8; 3]) -> String {
+    format!("{}, {}", pixel("c", 0., color), pixel("c", 100., color))
+}
+
+fn wave(colors: &[[u8; 3]], direction: Direction, steps: usize) -> Vec<String> {
+    if colors.is_empty() {
+        return Vec::new();
+    }
+    let (axis, sign) = match direction {
+        Direction::Right => ("c", 1.),
+        Direction::Left => ("c", -1.),
+        Direction::Down => ("r", 1.),
+        Direction::Up => ("r", -1.),
+    };
+
+    // The gradient repeats every 100% so a cycle loops seamlessly. Each stop keeps its color
+    //  and moves with the wave, the stops just outside the board are kept for interpolation.
+    let n = colors.len() as i32;
+    let spacing = 100. / n as f32;
+    (0..steps)
+        .map(|i| {
+            let shift = sign * 100. * i as f32 / steps as f32;
+            let first = ((-spacing - shift) / spacing).ceil() as i32;
+            let last = ((100. + spacing - shift) / spacing).floor() as i32;
+            (first..=last)
+                .map(|j| {
+                    let color = colors[j.rem_euclid(n) as usize];
+                    pixel(axis, j as f32 * spacing + shift, color)
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .collect()
+}
+
+fn breathe(color: [u8; 3], steps: usize) -> Vec<String> {
+    (0..steps)
+        .map(|i| {
+            // Triangle wave from off to full brightness and back
+            let t = i as f32 / steps as f32;
+            let factor = 1. - (2. * t - 1.).abs();
+            fill(scale(color, factor))
+        })
+        .collect()
+}
+
+/// Column of the LED with a scan code, in percent of the LED columns
+pub fn led_column(leds: &[Led], scan_code: &str) -> Option<f32> {
+    let led = leds.iter().find(|l| {
+        l.scan_code
+            .as_ref()
+            .is_some_and(|c| c.eq_ignore_ascii_case(scan_code))
+    })?;
+    let min = leds.iter().map(|l| l.x).fold(f32::INFINITY, f32::min);
+    let max = leds.iter().map(|l| l.x).fold(f32::NEG_INFINITY, f32::max);
+    Some(if max > min {
+        (led.x - min) / (max - min) * 100.
+    } else {
+        50.
+    })
+}
+
+fn ripple(color: [u8; 3], steps: usize, speed: Option<f32>, center: f32) -> Vec<String> {
+    // The band expands until it reaches the farthest side of the board
+    let reach = center.max(100. - center);
+    let steps = match speed {
+        Some(speed) if speed > 0. => ((reach / speed).ceil() as usize).clamp(1, MAX_STEPS),
+        _ => steps,
+    };
+    (0..steps)
+        .map(|i| {
+            let t = i as f32 / steps as f32;
+            let radius = reach * t;
+            let band = scale(color, 1. - t);
+
+            // Off on both sides of the band, inside only once it's wide enough
+            let mut stops = vec![(-radius - RIPPLE_WIDTH, OFF), (-radius, band)];
+            if radius > RIPPLE_WIDTH {
+                stops.push((-radius + RIPPLE_WIDTH, OFF));
+                stops.push((radius - RIPPLE_WIDTH, OFF));
+            }
+            stops.push((radius, band));
+            stops.push((radius + RIPPLE_WIDTH, OFF));
+            stops
+                .into_iter()
+                .map(|(pos, color)| pixel("c", center + pos, color))
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .collect()
+}
+
+/// Expands a generator into KLL animation frames, `leds` places the ripple of a key
+pub fn generate_frames(generator: &Generator, leds: &[Led]) -> Vec<String> {
+    match generator {
+        Generator::Wave {
+            colors,
+            direction,
+            steps,
+        } => wave(colors, *direction, *steps),
+        Generator::Breathe { color, steps } => breathe(*color, *steps),
+        Generator::Solid { color } => vec![fill(*color)],
+        Generator::Ripple {
+            color,
+            steps,
+            speed,
+            key,
+        } => {
+            let center = key.as_ref().and_then(|k| led_column(leds, k));
+            ripple(*color, *steps, *speed, center.unwrap_or(50.))
+        }
+    }
+}
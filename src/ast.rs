@@ -0,0 +1,86 @@
+use std::fmt;
+
+/// A single KLL statement. Triggers and results are kept as KLL expressions
+/// (e.g. `U"A"`, `A[name]`), see `format_key` and `format_trigger`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+    /// `Name = "value";`
+    Assignment { name: String, value: String },
+    /// `U"A" : U"B";`
+    Remap { trigger: String, result: String },
+    /// `U"A" :+ A[name];`
+    Trigger { trigger: String, result: String },
+    /// `A[name] <= settings;`
+    AnimationSettings { name: String, settings: String },
+    /// `A[name, n] <= frame;`
+    AnimationFrame {
+        name: String,
+        index: usize,
+        frame: String,
+    },
+    /// `P[n](channels) : S0x12;`
+    PixelMap {
+        index: usize,
+        channels: String,
+        scan_code: String,
+    },
+    /// `P[n] <= x:0, y:0;`
+    PixelPosition { index: usize, position: String },
+    /// A statement that is emitted commented out, `#U"A" : U"B";`
+    Disabled(Box<Statement>),
+    /// `#text`
+    Comment(String),
+    /// Empty line
+    Blank,
+    /// Verbatim KLL (e.g. custom blocks), printed as-is
+    Raw(String),
+    /// Any other statement, kept verbatim (without the trailing `;`)
+    Other(String),
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Statement::Assignment { name, value } => write!(f, "{} = \"{}\";", name, value),
+            Statement::Remap { trigger, result } => write!(f, "{} : {};", trigger, result),
+            Statement::Trigger { trigger, result } => write!(f, "{} :+ {};", trigger, result),
+            Statement::AnimationSettings { name, settings } => {
+                write!(f, "A[{}] <= {};", name, settings)
+            }
+            Statement::AnimationFrame { name, index, frame } => {
+                write!(f, "A[{}, {}] <= {};", name, index, frame)
+            }
+            Statement::PixelMap {
+                index,
+                channels,
+                scan_code,
+            } => write!(f, "P[{}]({}) : {};", index, channels, scan_code),
+            Statement::PixelPosition { index, position } => {
+                write!(f, "P[{}] <= {};", index, position)
+            }
+            Statement::Disabled(s) => write!(f, "#{}", s),
+            Statement::Comment(text) => write!(f, "#{}", text),
+            Statement::Blank => Ok(()),
+            Statement::Raw(text) => write!(f, "{}", text),
+            Statement::Other(text) => write!(f, "{};", text),
+        }
+    }
+}
+
+/// A generated .kll file. Each section is printed one statement per line, sections are
+/// separated by an empty line.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KllDocument {
+    pub name: String,
+    pub sections: Vec<Vec<Statement>>,
+}
+
+impl fmt::Display for KllDocument {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for section in self.sections.iter() {
+            let lines = section.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            write!(f, "{}\n\n", lines.join("\n"))?;
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
 use crate::kll::KllConfig;
 
 use crate::kll::*;
+use serde::Serialize;
 use shared_child::SharedChild;
-use std::process::Command;
-use std::path::Path;
 use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
 
 #[derive(Debug)]
 pub struct BuildInfo {
@@ -46,15 +47,10 @@ pub fn configure_build(config: &KllConfig, layers: Vec<String>) -> BuildInfo {
     }
     .to_string();
 
-    let split_keyboard = match name.to_lowercase().as_ref() {
-        "mdergo1" => true,
-        _ => false,
-    };
+    let split_keyboard = matches!(name.to_lowercase().as_ref(), "mdergo1");
 
     let extra_map = match name.to_lowercase().as_ref() {
-        "mdergo1" => vec![
-            "infinity_ergodox/lcdFuncMap".to_string(),
-        ],
+        "mdergo1" => vec!["infinity_ergodox/lcdFuncMap".to_string()],
         _ => vec!["stdFuncMap".to_string()],
     };
 
@@ -79,7 +75,10 @@ pub fn configure_build(config: &KllConfig, layers: Vec<String>) -> BuildInfo {
         .map(|l| {
             //let mut layer = extra_map.clone();
             let mut layer = vec![];
-            let partial_layer = Path::new(&l).file_stem().unwrap_or(OsStr::new("")).to_os_string();
+            let partial_layer = Path::new(&l)
+                .file_stem()
+                .unwrap_or(OsStr::new(""))
+                .to_os_string();
             layer.push(partial_layer.into_string().unwrap());
             kll_layer(layer)
         })
@@ -129,18 +128,74 @@ pub fn start_build(
     let process = SharedChild::spawn(&mut compile).expect("docker compose failed to run container");
 
     println!(" >> Created PID: {} ({})", process.id(), container);
-    return process;
+    process
+}
+
+/// A service of compose.yaml and the state of its image
+#[derive(Clone, Debug, Serialize)]
+pub struct ContainerImage {
+    pub service: String,
+    pub image: String,
+    /// Whether the image exists locally, builds fail without it
+    pub available: bool,
+    /// Image ID
+    pub digest: Option<String>,
+    /// Image size in bytes
+    pub size: Option<u64>,
 }
 
-pub fn list_containers() -> Vec<String> {
+/// Services and their image names from `docker compose config --format json`. Services
+/// without an `image` use compose's default `<project>-<service>` name.
+pub fn compose_images(config: &str) -> Vec<(String, String)> {
+    let config: serde_json::Value = serde_json::from_str(config).unwrap_or_default();
+    let project = config["name"].as_str().unwrap_or("kiisrv");
+    config["services"]
+        .as_object()
+        .map(|services| {
+            services
+                .iter()
+                .filter(|(s, _)| !s.contains("template"))
+                .map(|(s, v)| {
+                    let image = match v["image"].as_str() {
+                        Some(image) => image.to_string(),
+                        None => format!("{}-{}", project, s),
+                    };
+                    (s.clone(), image)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Parses `docker image inspect --format "{{.Id}} {{.Size}}"` into the ID and size
+pub fn parse_image_inspect(out: &str) -> Option<(String, u64)> {
+    let (id, size) = out.trim().split_once(' ')?;
+    Some((id.to_string(), size.parse().ok()?))
+}
+
+/// Compose services with the state of their images
+pub fn container_images() -> Vec<ContainerImage> {
     let result = Command::new("docker")
-        .args(&["compose", "config", "--services"])
+        .args(["compose", "config", "--format", "json"])
         .output()
         .expect("Please install docker compose");
-    let out = String::from_utf8_lossy(&result.stdout);
-    out.lines()
-        .filter(|s| !s.contains("template"))
-        .map(|s| s.to_string())
+    compose_images(&String::from_utf8_lossy(&result.stdout))
+        .into_iter()
+        .map(|(service, image)| {
+            let inspect = Command::new("docker")
+                .args(["image", "inspect", "--format", "{{.Id}} {{.Size}}", &image])
+                .output()
+                .ok()
+                .filter(|result| result.status.success())
+                .and_then(|result| parse_image_inspect(&String::from_utf8_lossy(&result.stdout)));
+            ContainerImage {
+                service,
+                image,
+                available: inspect.is_some(),
+                digest: inspect.as_ref().map(|(id, _)| id.clone()),
+                size: inspect.map(|(_, size)| size),
+            }
+        })
         .collect()
 }
 
@@ -148,7 +203,7 @@ pub fn list_containers() -> Vec<String> {
 #[allow(dead_code)]
 pub fn get_builds(service: &str) -> String {
     let result = Command::new("docker")
-        .args(&[
+        .args([
             "compose",
             "run",
             "--rm",
@@ -167,7 +222,7 @@ pub fn get_builds(service: &str) -> String {
 #[allow(dead_code)]
 fn old_builds(service: &str) {
     let status = Command::new("docker")
-        .args(&[
+        .args([
             "compose",
             "run",
             "--rm",
@@ -0,0 +1,114 @@
+/// Arguments taken by the firmware's `ledControl` result function
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LedControl {
+    /// `ledControl( mode, amount )`
+    ModeAmount,
+    /// `ledControl( mode, amount, index )`, with the old mode numbering
+    ModeAmountIndex,
+}
+
+/// What a firmware version can build, used to adapt the generated KLL.
+pub struct FirmwareProfile {
+    /// Controller git tag
+    pub version: &'static str,
+    /// KLL spec version supported by the compiler
+    pub kll: &'static str,
+    /// Scan code version, see `SCAN_CODE_MAPS`
+    pub scan_codes: &'static str,
+    pub animations: bool,
+    /// `:+` trigger statements
+    pub triggers: bool,
+    /// Press/hold/release state scheduling
+    pub scheduling: bool,
+    /// Pixel maps and positions
+    pub leds: bool,
+    pub led_control: LedControl,
+    /// Layers the layer state can address, boards may allow fewer, see `board_limits`
+    pub max_layers: usize,
+    /// Result functions that don't exist in this version, bindings using them are commented out
+    pub unsupported_results: &'static [&'static str],
+}
+
+pub const FIRMWARE_PROFILES: &[FirmwareProfile] = &[
+    FirmwareProfile {
+        version: "v0.5.0",
+        kll: "0.3",
+        scan_codes: "lts",
+        animations: false,
+        triggers: false,
+        scheduling: false,
+        leds: false,
+        led_control: LedControl::ModeAmountIndex,
+        max_layers: 8,
+        unsupported_results: &["animation_control"],
+    },
+    FirmwareProfile {
+        version: "v0.5.4",
+        kll: "0.5",
+        scan_codes: "latest",
+        animations: true,
+        triggers: true,
+        scheduling: true,
+        leds: true,
+        led_control: LedControl::ModeAmount,
+        max_layers: 16,
+        unsupported_results: &[],
+    },
+    FirmwareProfile {
+        version: "v0.5.5",
+        kll: "0.5",
+        scan_codes: "latest",
+        animations: true,
+        triggers: true,
+        scheduling: true,
+        leds: true,
+        led_control: LedControl::ModeAmount,
+        max_layers: 16,
+        unsupported_results: &[],
+    },
+    FirmwareProfile {
+        version: "v0.5.6",
+        kll: "0.5",
+        scan_codes: "latest",
+        animations: true,
+        triggers: true,
+        scheduling: true,
+        leds: true,
+        led_control: LedControl::ModeAmount,
+        max_layers: 16,
+        unsupported_results: &[],
+    },
+    FirmwareProfile {
+        version: "v0.5.7",
+        kll: "0.5",
+        scan_codes: "latest",
+        animations: true,
+        triggers: true,
+        scheduling: true,
+        leds: true,
+        led_control: LedControl::ModeAmount,
+        max_layers: 16,
+        unsupported_results: &[],
+    },
+];
+
+/// Profile of a firmware version or channel (`latest`, `nightly`, `lts`). Unknown versions
+/// are assumed to be as capable as the latest release.
+pub fn firmware_profile(version: &str) -> &'static FirmwareProfile {
+    let version = match version {
+        "lts" => "v0.5.0",
+        "latest" | "nightly" => "v0.5.7",
+        v => v,
+    };
+    FIRMWARE_PROFILES
+        .iter()
+        .find(|p| p.version == version)
+        .unwrap_or(&FIRMWARE_PROFILES[FIRMWARE_PROFILES.len() - 1])
+}
+
+impl FirmwareProfile {
+    /// Whether a `#:` result can be built, e.g. `animation_control( 3 )`
+    pub fn supports_result(&self, result: &str) -> bool {
+        !self.unsupported_results.iter().any(|f| result.contains(f))
+    }
+}
@@ -0,0 +1,410 @@
+use crate::kll::*;
+
+use serde::Serialize;
+
+/// USB HID Keyboard/Keypad page (0x07) usages. The first name is the canonical one used by
+/// the configurator, the rest are aliases accepted by the KLL compiler.
+/// Names are matched case-insensitively, ignoring spaces and underscores.
+pub const KEYBOARD_USAGES: &[(u16, &[&str])] = &[
+    (0x04, &["A"]),
+    (0x05, &["B"]),
+    (0x06, &["C"]),
+    (0x07, &["D"]),
+    (0x08, &["E"]),
+    (0x09, &["F"]),
+    (0x0A, &["G"]),
+    (0x0B, &["H"]),
+    (0x0C, &["I"]),
+    (0x0D, &["J"]),
+    (0x0E, &["K"]),
+    (0x0F, &["L"]),
+    (0x10, &["M"]),
+    (0x11, &["N"]),
+    (0x12, &["O"]),
+    (0x13, &["P"]),
+    (0x14, &["Q"]),
+    (0x15, &["R"]),
+    (0x16, &["S"]),
+    (0x17, &["T"]),
+    (0x18, &["U"]),
+    (0x19, &["V"]),
+    (0x1A, &["W"]),
+    (0x1B, &["X"]),
+    (0x1C, &["Y"]),
+    (0x1D, &["Z"]),
+    (0x1E, &["1"]),
+    (0x1F, &["2"]),
+    (0x20, &["3"]),
+    (0x21, &["4"]),
+    (0x22, &["5"]),
+    (0x23, &["6"]),
+    (0x24, &["7"]),
+    (0x25, &["8"]),
+    (0x26, &["9"]),
+    (0x27, &["0"]),
+    (0x28, &["ENTER", "RETURN"]),
+    (0x29, &["ESC", "ESCAPE"]),
+    (0x2A, &["BACKSPACE"]),
+    (0x2B, &["TAB"]),
+    (0x2C, &["SPACE", "SPACEBAR"]),
+    (0x2D, &["MINUS", "-"]),
+    (0x2E, &["EQUAL", "EQUALS", "="]),
+    (0x2F, &["LBRACE", "LEFT BRACE", "LBRACKET", "["]),
+    (0x30, &["RBRACE", "RIGHT BRACE", "RBRACKET", "]"]),
+    (0x31, &["BACKSLASH", "\\"]),
+    (0x32, &["HASH", "NUMBER", "ISO#"]),
+    (0x33, &["SEMICOLON", ";"]),
+    (0x34, &["QUOTE", "APOSTROPHE", "'"]),
+    (0x35, &["BACKTICK", "TILDE", "GRAVE", "`"]),
+    (0x36, &["COMMA", ","]),
+    (0x37, &["PERIOD", "."]),
+    (0x38, &["SLASH", "/"]),
+    (0x39, &["CAPSLOCK", "CAPS LOCK"]),
+    (0x3A, &["F1"]),
+    (0x3B, &["F2"]),
+    (0x3C, &["F3"]),
+    (0x3D, &["F4"]),
+    (0x3E, &["F5"]),
+    (0x3F, &["F6"]),
+    (0x40, &["F7"]),
+    (0x41, &["F8"]),
+    (0x42, &["F9"]),
+    (0x43, &["F10"]),
+    (0x44, &["F11"]),
+    (0x45, &["F12"]),
+    (0x46, &["PRINTSCREEN", "PRINT SCREEN"]),
+    (0x47, &["SCROLLLOCK", "SCROLL LOCK"]),
+    (0x48, &["PAUSE"]),
+    (0x49, &["INSERT"]),
+    (0x4A, &["HOME"]),
+    (0x4B, &["PAGEUP", "PAGE UP"]),
+    (0x4C, &["DELETE"]),
+    (0x4D, &["END"]),
+    (0x4E, &["PAGEDOWN", "PAGE DOWN"]),
+    (0x4F, &["RIGHT"]),
+    (0x50, &["LEFT"]),
+    (0x51, &["DOWN"]),
+    (0x52, &["UP"]),
+    (0x53, &["NUMLOCK", "NUM LOCK"]),
+    (0x54, &["P/", "KEYPAD SLASH"]),
+    (0x55, &["P*", "KEYPAD ASTERISK"]),
+    (0x56, &["P-", "KEYPAD MINUS"]),
+    (0x57, &["P+", "KEYPAD PLUS"]),
+    (0x58, &["PENTER", "KEYPAD ENTER"]),
+    (0x59, &["P1", "KEYPAD 1"]),
+    (0x5A, &["P2", "KEYPAD 2"]),
+    (0x5B, &["P3", "KEYPAD 3"]),
+    (0x5C, &["P4", "KEYPAD 4"]),
+    (0x5D, &["P5", "KEYPAD 5"]),
+    (0x5E, &["P6", "KEYPAD 6"]),
+    (0x5F, &["P7", "KEYPAD 7"]),
+    (0x60, &["P8", "KEYPAD 8"]),
+    (0x61, &["P9", "KEYPAD 9"]),
+    (0x62, &["P0", "KEYPAD 0"]),
+    (0x63, &["P.", "KEYPAD PERIOD"]),
+    (0x64, &["ISO/", "ISO SLASH"]),
+    (0x65, &["APP", "APPLICATION"]),
+    (0x66, &["POWER"]),
+    (0x67, &["P=", "KEYPAD EQUAL"]),
+    (0x68, &["F13"]),
+    (0x69, &["F14"]),
+    (0x6A, &["F15"]),
+    (0x6B, &["F16"]),
+    (0x6C, &["F17"]),
+    (0x6D, &["F18"]),
+    (0x6E, &["F19"]),
+    (0x6F, &["F20"]),
+    (0x70, &["F21"]),
+    (0x71, &["F22"]),
+    (0x72, &["F23"]),
+    (0x73, &["F24"]),
+    (0x74, &["EXEC", "EXECUTE"]),
+    (0x75, &["HELP"]),
+    (0x76, &["MENU"]),
+    (0x77, &["SELECT"]),
+    (0x78, &["STOP"]),
+    (0x79, &["AGAIN"]),
+    (0x7A, &["UNDO"]),
+    (0x7B, &["CUT"]),
+    (0x7C, &["COPY"]),
+    (0x7D, &["PASTE"]),
+    (0x7E, &["FIND"]),
+    (0x7F, &["MUTE"]),
+    (0x80, &["VOLUMEUP", "VOLUME UP"]),
+    (0x81, &["VOLUMEDOWN", "VOLUME DOWN"]),
+    (0x82, &["LCK1", "LOCKING CAPS LOCK"]),
+    (0x83, &["LCK2", "LOCKING NUM LOCK"]),
+    (0x84, &["LCK3", "LOCKING SCROLL LOCK"]),
+    (0x85, &["P,", "KEYPAD COMMA"]),
+    (0x86, &["P=AS400", "KEYPAD EQUAL AS400"]),
+    (0x87, &["INTERNATIONAL1", "INT1"]),
+    (0x88, &["INTERNATIONAL2", "INT2"]),
+    (0x89, &["INTERNATIONAL3", "INT3"]),
+    (0x8A, &["INTERNATIONAL4", "INT4"]),
+    (0x8B, &["INTERNATIONAL5", "INT5"]),
+    (0x8C, &["INTERNATIONAL6", "INT6"]),
+    (0x8D, &["INTERNATIONAL7", "INT7"]),
+    (0x8E, &["INTERNATIONAL8", "INT8"]),
+    (0x8F, &["INTERNATIONAL9", "INT9"]),
+    (0x90, &["LANG1"]),
+    (0x91, &["LANG2"]),
+    (0x92, &["LANG3"]),
+    (0x93, &["LANG4"]),
+    (0x94, &["LANG5"]),
+    (0x95, &["LANG6"]),
+    (0x96, &["LANG7"]),
+    (0x97, &["LANG8"]),
+    (0x98, &["LANG9"]),
+    (0x99, &["ALTERASE", "ALT ERASE"]),
+    (0x9A, &["SYSREQ", "SYS REQ", "ATTENTION"]),
+    (0x9B, &["CANCEL"]),
+    (0x9C, &["CLEAR"]),
+    (0x9D, &["PRIOR"]),
+    (0x9E, &["RETURN2"]),
+    (0x9F, &["SEPARATOR"]),
+    (0xA0, &["OUT"]),
+    (0xA1, &["OPER"]),
+    (0xA2, &["CLEARAGAIN", "CLEAR AGAIN"]),
+    (0xA3, &["CRSEL", "PROPS"]),
+    (0xA4, &["EXSEL"]),
+    (0xB0, &["P00", "KEYPAD 00"]),
+    (0xB1, &["P000", "KEYPAD 000"]),
+    (0xB6, &["P(", "KEYPAD LEFT PARENTHESES"]),
+    (0xB7, &["P)", "KEYPAD RIGHT PARENTHESES"]),
+    (0xBA, &["PTAB", "KEYPAD TAB"]),
+    (0xBB, &["PBACKSPACE", "KEYPAD BACKSPACE"]),
+    (0xD7, &["P+-", "KEYPAD PLUS MINUS"]),
+    (0xD8, &["PCLEAR", "KEYPAD CLEAR"]),
+    (0xE0, &["LCTRL", "CTRL", "LEFT CTRL", "CONTROL"]),
+    (0xE1, &["LSHIFT", "SHIFT", "LEFT SHIFT"]),
+    (0xE2, &["LALT", "ALT", "LEFT ALT", "OPT", "OPTION"]),
+    (0xE3, &["LGUI", "GUI", "LEFT GUI", "CMD", "SUPER", "WIN"]),
+    (0xE4, &["RCTRL", "RIGHT CTRL"]),
+    (0xE5, &["RSHIFT", "RIGHT SHIFT"]),
+    (0xE6, &["RALT", "RIGHT ALT"]),
+    (0xE7, &["RGUI", "RIGHT GUI"]),
+    // Kiibohd function keys, handled by the firmware's layer maps
+    (0xF0, &["FUNCTION1", "FUN1"]),
+    (0xF1, &["FUNCTION2", "FUN2"]),
+    (0xF2, &["FUNCTION3", "FUN3"]),
+    (0xF3, &["FUNCTION4", "FUN4"]),
+    (0xF4, &["FUNCTION5", "FUN5"]),
+    (0xF5, &["FUNCTION6", "FUN6"]),
+    (0xF6, &["FUNCTION7", "FUN7"]),
+    (0xF7, &["FUNCTION8", "FUN8"]),
+    (0xF8, &["FUNCTION9", "FUN9"]),
+    (0xF9, &["FUNCTION10", "FUN10"]),
+    (0xFA, &["FUNCTION11", "FUN11"]),
+    (0xFB, &["FUNCTION12", "FUN12"]),
+    (0xFC, &["FUNCTION13", "FUN13"]),
+    (0xFD, &["FUNCTION14", "FUN14"]),
+    (0xFE, &["FUNCTION15", "FUN15"]),
+    (0xFF, &["FUNCTION16", "FUN16"]),
+];
+
+/// USB HID Consumer page (0x0C) usages, `CONS:` keys.
+pub const CONSUMER_USAGES: &[(u16, &[&str])] = &[
+    (0x030, &["POWER"]),
+    (0x032, &["SLEEP"]),
+    (0x040, &["MENU"]),
+    (
+        0x06F,
+        &[
+            "BRIGHTNESSINCREMENT",
+            "BRIGHTNESS INCREMENT",
+            "BRIGHTNESS UP",
+        ],
+    ),
+    (
+        0x070,
+        &[
+            "BRIGHTNESSDECREMENT",
+            "BRIGHTNESS DECREMENT",
+            "BRIGHTNESS DOWN",
+        ],
+    ),
+    (0x0B0, &["PLAY"]),
+    (0x0B1, &["PAUSE"]),
+    (0x0B2, &["RECORD"]),
+    (0x0B3, &["FASTFORWARD", "FAST FORWARD"]),
+    (0x0B4, &["REWIND"]),
+    (0x0B5, &["SCANNEXTTRACK", "SCAN NEXT TRACK", "NEXT TRACK"]),
+    (
+        0x0B6,
+        &["SCANPREVIOUSTRACK", "SCAN PREVIOUS TRACK", "PREVIOUS TRACK"],
+    ),
+    (0x0B7, &["STOP"]),
+    (0x0B8, &["EJECT"]),
+    (0x0B9, &["RANDOMPLAY", "RANDOM PLAY", "SHUFFLE"]),
+    (0x0BC, &["REPEAT"]),
+    (
+        0x0CD,
+        &["PAUSEPLAY", "PAUSE PLAY", "PLAY PAUSE", "PLAYPAUSE"],
+    ),
+    (0x0E2, &["MUTE"]),
+    (0x0E9, &["VOLUMEUP", "VOLUME UP", "VOLUME INCREMENT"]),
+    (0x0EA, &["VOLUMEDOWN", "VOLUME DOWN", "VOLUME DECREMENT"]),
+    (
+        0x183,
+        &[
+            "CONSUMERCONTROLCONFIG",
+            "CONSUMER CONTROL CONFIG",
+            "MEDIA SELECT",
+        ],
+    ),
+    (0x18A, &["EMAIL", "EMAIL READER", "MAIL"]),
+    (0x192, &["CALCULATOR"]),
+    (0x194, &["FILEBROWSER", "FILE BROWSER", "MY COMPUTER"]),
+    (0x196, &["INTERNETBROWSER", "INTERNET BROWSER", "WWW"]),
+    (0x19E, &["TERMINALLOCK", "TERMINAL LOCK", "SCREENSAVER"]),
+    (0x1A6, &["HELP", "HELP CENTER"]),
+    (0x221, &["SEARCH", "AC SEARCH"]),
+    (0x223, &["HOME", "AC HOME"]),
+    (0x224, &["BACK", "AC BACK"]),
+    (0x225, &["FORWARD", "AC FORWARD"]),
+    (0x226, &["STOPBROWSER", "AC STOP"]),
+    (0x227, &["REFRESH", "AC REFRESH"]),
+    (0x22A, &["BOOKMARKS", "AC BOOKMARKS"]),
+];
+
+/// USB HID Generic Desktop page (0x01) system control usages, `SYS:` keys.
+pub const SYSTEM_USAGES: &[(u16, &[&str])] = &[
+    (0x81, &["POWERDOWN", "POWER DOWN"]),
+    (0x82, &["SLEEP"]),
+    (0x83, &["WAKEUP", "WAKE UP"]),
+    (0x84, &["CONTEXTMENU", "CONTEXT MENU"]),
+    (0x85, &["MAINMENU", "MAIN MENU"]),
+    (0x86, &["APPMENU", "APP MENU"]),
+    (0x87, &["MENUHELP", "MENU HELP"]),
+    (0x88, &["MENUEXIT", "MENU EXIT"]),
+    (0x89, &["MENUSELECT", "MENU SELECT"]),
+    (0x8A, &["MENURIGHT", "MENU RIGHT"]),
+    (0x8B, &["MENULEFT", "MENU LEFT"]),
+    (0x8C, &["MENUUP", "MENU UP"]),
+    (0x8D, &["MENUDOWN", "MENU DOWN"]),
+    (0x8E, &["COLDRESTART", "COLD RESTART"]),
+    (0x8F, &["WARMRESTART", "WARM RESTART"]),
+    (0x90, &["DPADUP", "DPAD UP"]),
+    (0x91, &["DPADDOWN", "DPAD DOWN"]),
+    (0x92, &["DPADRIGHT", "DPAD RIGHT"]),
+    (0x93, &["DPADLEFT", "DPAD LEFT"]),
+    (0xA0, &["DOCK"]),
+    (0xA1, &["UNDOCK"]),
+    (0xA2, &["SETUP"]),
+    (0xA3, &["BREAK"]),
+    (0xA4, &["DEBUGGERBREAK", "DEBUGGER BREAK"]),
+    (0xA5, &["APPLICATIONBREAK", "APPLICATION BREAK"]),
+    (
+        0xA6,
+        &["APPLICATIONDEBUGGERBREAK", "APPLICATION DEBUGGER BREAK"],
+    ),
+    (0xA7, &["SPEAKERMUTE", "SPEAKER MUTE"]),
+    (0xA8, &["HIBERNATE"]),
+    (0xB0, &["DISPLAYINVERT", "DISPLAY INVERT"]),
+    (0xB1, &["DISPLAYINTERNAL", "DISPLAY INTERNAL"]),
+    (0xB2, &["DISPLAYEXTERNAL", "DISPLAY EXTERNAL"]),
+    (0xB3, &["DISPLAYBOTH", "DISPLAY BOTH"]),
+    (0xB4, &["DISPLAYDUAL", "DISPLAY DUAL"]),
+    (0xB5, &["DISPLAYTOGGLE", "DISPLAY TOGGLE"]),
+    (0xB6, &["DISPLAYSWAP", "DISPLAY SWAP"]),
+    (0xB7, &["DISPLAYAUTOSCALE", "DISPLAY AUTOSCALE"]),
+];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyError {
+    pub code: String,
+    pub layer: usize,
+    pub key: String,
+    pub suggestion: Option<String>,
+}
+
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| *c != ' ' && *c != '_')
+        .flat_map(|c| c.to_uppercase())
+        .collect()
+}
+
+/// The usage table and name of a JSON key (`A`, `CONS:MUTE`, `SYS:SLEEP`)
+fn usage_table(key: &str) -> (&'static [(u16, &'static [&'static str])], &str, &str) {
+    if let Some(k) = key.strip_prefix("CONS:") {
+        (CONSUMER_USAGES, "CONS:", k)
+    } else if let Some(k) = key.strip_prefix("SYS:") {
+        (SYSTEM_USAGES, "SYS:", k)
+    } else {
+        (KEYBOARD_USAGES, "", key)
+    }
+}
+
+/// Looks up the HID usage id of a JSON key
+pub fn lookup_key(key: &str) -> Option<u16> {
+    let (table, _, name) = usage_table(key);
+    let name = normalize(name);
+    table
+        .iter()
+        .find(|(_, names)| names.iter().any(|n| normalize(n) == name))
+        .map(|(id, _)| *id)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(row[j + 1])
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// The closest canonical key name to an unknown key, if any is reasonably close
+pub fn suggest_key(key: &str) -> Option<String> {
+    let (table, prefix, name) = usage_table(key);
+    let name = normalize(name);
+    let name = name.as_str();
+    let max_distance = (name.chars().count() / 3).max(1);
+
+    table
+        .iter()
+        .flat_map(|(_, names)| {
+            names
+                .iter()
+                .map(move |n| (names[0], edit_distance(name, &normalize(n))))
+        })
+        .filter(|(_, d)| *d <= max_distance)
+        .min_by_key(|(_, d)| *d)
+        .map(|(canonical, _)| format!("{}{}", prefix, canonical))
+}
+
+/// Checks every binding in the config against the usage catalog.
+/// Result functions (`#:...`), unicode code points (`U+2318`) and empty keys are not checked.
+pub fn validate_keys(config: &KllConfig) -> Vec<KeyError> {
+    let mut errors = Vec::new();
+    for key in config.matrix.iter() {
+        for (l, action) in key.layers.iter() {
+            let scheduled = action.hold.iter().chain(action.release.iter()).cloned();
+            for key_name in action.combos().into_iter().flatten().chain(scheduled) {
+                if key_name.is_empty() || key_name.starts_with("#:") || key_name.starts_with("U+") {
+                    continue;
+                }
+                if lookup_key(&key_name).is_none() {
+                    errors.push(KeyError {
+                        code: key.code.clone(),
+                        layer: *l,
+                        suggestion: suggest_key(&key_name),
+                        key: key_name,
+                    });
+                }
+            }
+        }
+    }
+    errors
+}
@@ -0,0 +1,625 @@
+use crate::ast::*;
+use crate::kll::*;
+
+use indexmap::IndexMap;
+use serde::Serialize;
+
+const HEADER_FIELDS: [&str; 9] = [
+    "Name",
+    "Variant",
+    "Layout",
+    "Base",
+    "Version",
+    "Author",
+    "KLL",
+    "Date",
+    "Generator",
+];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImportError {
+    pub file: usize,
+    pub line: usize,
+    pub message: String,
+}
+
+/// A problem in a `custom` KLL block. Errors reject the config, warnings are built anyway.
+#[derive(Clone, Debug, Serialize)]
+pub struct CustomIssue {
+    pub layer: usize,
+    pub line: usize,
+    pub message: String,
+    pub error: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ParsedStatement {
+    pub line: usize,
+    pub statement: Statement,
+}
+
+/// Index of the first occurrence of `pat` that is not inside a `"..."` string or `(...)`
+/// arguments (e.g. a `(H:200ms)` schedule).
+fn find_unquoted(s: &str, pat: &str) -> Option<usize> {
+    let mut quoted = false;
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted && depth > 0 => depth -= 1,
+            _ if !quoted && depth == 0 && s[i..].starts_with(pat) => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unquote(s: &str) -> Option<&str> {
+    s.strip_prefix('"')?.strip_suffix('"')
+}
+
+/// Converts a KLL usage (`U"A"`, `CONS"MUTE"`, ...) back into the JSON key form.
+/// Anything else is a result function (e.g. `flashMode()`) and uses the `#:` prefix.
+pub fn unformat_key(s: &str) -> String {
+    let s = s.trim();
+    if let Some(k) = s.strip_prefix("CONS").and_then(unquote) {
+        format!("CONS:{}", k)
+    } else if let Some(k) = s.strip_prefix("SYS").and_then(unquote) {
+        format!("SYS:{}", k)
+    } else if let Some(k) = s.strip_prefix('U').and_then(unquote) {
+        k.to_string()
+    } else {
+        format!("#:{}", s)
+    }
+}
+
+/// Splits at `sep`, ignoring separators inside strings and `(...)`/`[...]` arguments
+pub(crate) fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' | '[' if !quoted => depth += 1,
+            ')' | ']' if !quoted => depth -= 1,
+            c if c == sep && !quoted && depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(s[start..].trim());
+    parts
+}
+
+/// Splits the state schedule (`U"A"(H:200ms)`) off a trigger
+fn split_schedule(trigger: &str) -> (&str, Option<&str>) {
+    match trigger.strip_suffix(')').and_then(|t| t.rsplit_once('(')) {
+        Some((trigger, schedule)) if trigger.ends_with('"') => (trigger, Some(schedule.trim())),
+        _ => (trigger, None),
+    }
+}
+
+/// Converts a remap result back into a JSON key action, combinations (`+`) and sequences
+/// (`,`) keep their first key in `key`.
+pub fn unformat_action(result: &str) -> KeyAction {
+    let mut combos = split_unquoted(result, ',')
+        .into_iter()
+        .map(|combo| {
+            split_unquoted(combo, '+')
+                .into_iter()
+                .map(unformat_key)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let key = combos[0][0].clone();
+    let (combo, sequence) = match (combos.len(), combos[0].len()) {
+        (1, 1) => (None, None),
+        (1, _) => (combos.pop(), None),
+        _ => (None, Some(combos)),
+    };
+    KeyAction {
+        key,
+        combo,
+        sequence,
+        ..Default::default()
+    }
+}
+
+/// Converts a trigger result back into a JSON trigger, the inverse of `format_trigger`
+pub fn unformat_trigger(result: &str) -> Trigger {
+    let result = result.trim();
+    for (prefix, _type) in [
+        ("A[", "animation"),
+        ("Layer[", "layer"),
+        ("LayerShift[", "layershift"),
+        ("LayerLock[", "layerlock"),
+        ("LayerLatch[", "layerlatch"),
+    ] {
+        if let Some(action) = result
+            .strip_prefix(prefix)
+            .and_then(|r| r.strip_suffix(']'))
+        {
+            if !action.contains(['[', ']', '(', '"']) {
+                return Trigger {
+                    action: action.to_string(),
+                    label: action.to_string(),
+                    _type: _type.to_string(),
+                };
+            }
+        }
+    }
+
+    Trigger {
+        action: result.to_string(),
+        label: result.to_string(),
+        _type: "kll".to_string(),
+    }
+}
+
+fn classify_pixel(rest: &str) -> Option<Statement> {
+    let end = rest.find(']')?;
+    let index = rest[..end].trim().parse().ok()?;
+    let rest = &rest[end + 1..];
+    if let Some(position) = rest.trim_start().strip_prefix("<=") {
+        return Some(Statement::PixelPosition {
+            index,
+            position: position.trim().to_string(),
+        });
+    }
+
+    let channels = rest.trim_start().strip_prefix('(')?;
+    let end = channels.find(')')?;
+    let scan_code = channels[end + 1..].trim_start().strip_prefix(':')?;
+    Some(Statement::PixelMap {
+        index,
+        channels: channels[..end].trim().to_string(),
+        scan_code: scan_code.trim().to_string(),
+    })
+}
+
+fn classify(text: &str) -> Statement {
+    if let Some(pixel) = text.strip_prefix("P[").and_then(classify_pixel) {
+        return pixel;
+    }
+    if let Some(rest) = text.strip_prefix("A[") {
+        if let (Some(end), Some(op)) = (rest.find(']'), find_unquoted(rest, "<=")) {
+            if end < op {
+                // Only drop the separator space, frames are often column aligned
+                let value = &rest[op + 2..];
+                let value = value
+                    .strip_prefix(' ')
+                    .unwrap_or(value)
+                    .trim_end()
+                    .to_string();
+                let mut args = rest[..end].split(',').map(|a| a.trim());
+                let name = args.next().unwrap_or("").to_string();
+                match args.next().map(|i| i.parse::<usize>()) {
+                    Some(Ok(index)) => {
+                        return Statement::AnimationFrame {
+                            name,
+                            index,
+                            frame: value,
+                        }
+                    }
+                    Some(Err(_)) => {}
+                    None => {
+                        return Statement::AnimationSettings {
+                            name,
+                            settings: value,
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(pos) = find_unquoted(text, ":+") {
+        return Statement::Trigger {
+            trigger: text[..pos].trim().to_string(),
+            result: text[pos + 2..].trim().to_string(),
+        };
+    }
+    if let Some(pos) = find_unquoted(text, ":") {
+        return Statement::Remap {
+            trigger: text[..pos].trim().to_string(),
+            result: text[pos + 1..].trim().to_string(),
+        };
+    }
+
+    if let Some(pos) = find_unquoted(text, "=") {
+        let name = text[..pos].trim();
+        if let Some(value) = unquote(text[pos + 1..].trim()) {
+            if !name.is_empty() && !name.contains(char::is_whitespace) {
+                return Statement::Assignment {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                };
+            }
+        }
+    }
+
+    Statement::Other(text.to_string())
+}
+
+/// Splits a .kll file into statements, dropping `#` comments.
+/// Line numbers are 1-based and point at the start of each statement.
+pub fn parse_statements(content: &str) -> Result<Vec<ParsedStatement>, ImportError> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut start_line = 1;
+    let mut line = 1;
+    let mut quoted = false;
+    let mut comment = false;
+
+    for c in content.chars() {
+        if c == '\n' {
+            line += 1;
+            comment = false;
+            quoted = false;
+        }
+        if comment {
+            continue;
+        }
+
+        match c {
+            '#' if !quoted => comment = true,
+            ';' if !quoted => {
+                let text = current.trim();
+                if !text.is_empty() {
+                    statements.push(ParsedStatement {
+                        line: start_line,
+                        statement: classify(text),
+                    });
+                }
+                current.clear();
+            }
+            _ => {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                if current.trim().is_empty() && !c.is_whitespace() {
+                    start_line = line;
+                }
+                current.push(c);
+            }
+        }
+    }
+
+    if !current.trim().is_empty() {
+        return Err(ImportError {
+            file: 0,
+            line: start_line,
+            message: "Missing ';' at end of statement".to_string(),
+        });
+    }
+
+    Ok(statements)
+}
+
+/// The `x` and `y` of a pixel position (`x:10, y:20`), other axes are ignored
+fn parse_position(position: &str) -> Option<(f32, f32)> {
+    let mut x = None;
+    let mut y = None;
+    for arg in position.split(',') {
+        match arg
+            .split_once(':')
+            .map(|(k, v)| (k.trim(), v.trim().parse::<f32>()))
+        {
+            Some(("x", Ok(v))) => x = Some(v),
+            Some(("y", Ok(v))) => y = Some(v),
+            _ => {}
+        }
+    }
+    Some((x?, y?))
+}
+
+fn led_mut(leds: &mut Vec<Led>, id: usize) -> &mut Led {
+    let idx = match leds.iter().position(|l| l.id == id) {
+        Some(idx) => idx,
+        None => {
+            leds.push(Led {
+                id,
+                scan_code: None,
+                x: 0.,
+                y: 0.,
+                channels: None,
+            });
+            leds.len() - 1
+        }
+    };
+    &mut leds[idx]
+}
+
+/// Rebuilds a `KllConfig` from a set of .kll layer files (layer 0 first) and the base
+/// layout they were generated against. Scan codes and positions come from the base layout,
+/// bindings, animations and defines come from the .kll files.
+pub fn import_kll(base: &KllConfig, files: &[String]) -> Result<KllConfig, ImportError> {
+    let mut config = base.clone();
+    config.custom = None;
+    config.animations = None;
+    config.canned = None;
+    config.defines = None;
+    for key in config.matrix.iter_mut() {
+        key.layers.retain(|l, _| *l == 0);
+        key.triggers = None;
+    }
+
+    let mut headers: IndexMap<String, String> = IndexMap::new();
+    let mut defines = Vec::new();
+    let mut animations: IndexMap<String, Animation> = IndexMap::new();
+    let mut custom: IndexMap<usize, String> = IndexMap::new();
+
+    // Bindings are keyed by the base layout's layer 0 key, config.matrix is a copy of the
+    //  base matrix so the same index applies to both
+    let base_index = |trigger: &str| {
+        let trigger = unformat_key(trigger);
+        base.matrix
+            .iter()
+            .position(|k| k.layers.get(&0).map(|a| &a.key) == Some(&trigger))
+            .ok_or_else(|| format!("{} is not in the base layout", trigger))
+    };
+
+    for (n, content) in files.iter().enumerate() {
+        let statements = parse_statements(content).map_err(|e| ImportError { file: n, ..e })?;
+        let mut other = Vec::new();
+
+        for s in statements {
+            let error = |message: String| ImportError {
+                file: n,
+                line: s.line,
+                message,
+            };
+
+            match s.statement {
+                Statement::Assignment { name, value } => {
+                    if HEADER_FIELDS.contains(&name.as_str()) {
+                        if n == 0 {
+                            headers.insert(name, value);
+                        }
+                    } else {
+                        defines.push(Define { name, value });
+                    }
+                }
+                Statement::Remap { trigger, result } => {
+                    let (trigger, schedule) = split_schedule(&trigger);
+                    let idx = base_index(trigger).map_err(error)?;
+
+                    // Scheduled remaps update the key's action, see generate_kll
+                    if let Some(schedule) = schedule {
+                        let (state, time) = schedule.split_once(':').unwrap_or((schedule, ""));
+                        let hold_time = time
+                            .strip_suffix("ms")
+                            .and_then(|t| t.parse::<u32>().ok())
+                            .filter(|t| *t != DEFAULT_HOLD_MS);
+                        let action = config.matrix[idx].layers.entry(n).or_default();
+                        match (state, time) {
+                            ("R", "") => action.release = Some(unformat_key(&result)),
+                            ("H", _) => {
+                                action.hold = Some(unformat_key(&result));
+                                action.hold_time = hold_time;
+                            }
+                            ("R", _) => {
+                                let tap = unformat_action(&result);
+                                if action.combos() != tap.combos() {
+                                    action.label = None;
+                                }
+                                action.key = tap.key;
+                                action.combo = tap.combo;
+                                action.sequence = tap.sequence;
+                            }
+                            _ => return Err(error(format!("Unsupported schedule ({})", schedule))),
+                        }
+                        continue;
+                    }
+
+                    let mut action = unformat_action(&result);
+                    action.label = base.matrix[idx]
+                        .layers
+                        .get(&n)
+                        .filter(|a| a.combos() == action.combos())
+                        .and_then(|a| a.label.clone());
+                    config.matrix[idx].layers.insert(n, action);
+                }
+                Statement::Trigger { trigger, result } => {
+                    let idx = base_index(&trigger).map_err(error)?;
+                    config.matrix[idx]
+                        .triggers
+                        .get_or_insert_with(IndexMap::new)
+                        .insert(n, unformat_trigger(&result));
+                }
+                Statement::AnimationSettings { name, settings } => {
+                    animations
+                        .entry(name)
+                        .or_insert(Animation {
+                            _type: None,
+                            frames: Vec::new(),
+                            settings: String::new(),
+                            generator: None,
+                        })
+                        .settings = settings;
+                }
+                Statement::AnimationFrame { name, frame, .. } => {
+                    animations
+                        .get_mut(&name)
+                        .ok_or_else(|| error(format!("Frame for undefined animation {}", name)))?
+                        .frames
+                        .push(frame);
+                }
+                Statement::PixelMap {
+                    index,
+                    channels,
+                    scan_code,
+                } => {
+                    let led = led_mut(config.leds.get_or_insert_with(Vec::new), index);
+                    led.channels = Some(channels);
+                    led.scan_code = Some(scan_code.trim_start_matches('S').to_string());
+                }
+                Statement::PixelPosition { index, position } => match parse_position(&position) {
+                    Some((x, y)) => {
+                        let led = led_mut(config.leds.get_or_insert_with(Vec::new), index);
+                        led.x = x;
+                        led.y = y;
+                    }
+                    None => other.push(format!("P[{}] <= {};", index, position)),
+                },
+                Statement::Other(_) => other.push(s.statement.to_string()),
+                // Not produced by parse_statements
+                Statement::Disabled(_)
+                | Statement::Comment(_)
+                | Statement::Blank
+                | Statement::Raw(_) => {}
+            }
+        }
+
+        if !other.is_empty() {
+            custom.insert(n, other.join("\n"));
+        }
+    }
+
+    let header = |name: &str| headers.get(name).cloned().unwrap_or_default();
+    let variant = header("Variant");
+    config.header = KllHeader {
+        name: header("Name"),
+        variant: if variant.is_empty() {
+            None
+        } else {
+            Some(variant)
+        },
+        layout: header("Layout"),
+        base: header("Base"),
+        version: header("Version"),
+        author: header("Author"),
+        kll: header("KLL"),
+        date: header("Date"),
+        generator: header("Generator"),
+        other: serde_json::Map::new(),
+    };
+
+    if !defines.is_empty() {
+        config.defines = Some(defines);
+    }
+    if !animations.is_empty() {
+        config.animations = Some(animations);
+    }
+    if !custom.is_empty() {
+        config.custom = Some(custom);
+    }
+
+    Ok(config)
+}
+
+/// The base layout file (`<Name>-<Base>.json`) referenced by a .kll file's header.
+pub fn base_layout_file(content: &str) -> Option<String> {
+    let statements = parse_statements(content).ok()?;
+    let header = |field: &str| {
+        statements.iter().find_map(|s| match &s.statement {
+            Statement::Assignment { name, value } if name == field => Some(value.clone()),
+            _ => None,
+        })
+    };
+    Some(format!(
+        "{}-{}.json",
+        header("Name")?.replace(' ', "_"),
+        header("Base")?
+    ))
+}
+
+/// Checks the `custom` blocks of a config with the KLL grammar used by the importer, against
+/// the `documents` generated for the firmware being built. Redefining headers or unparsable
+/// blocks are errors, overriding generated bindings or animations is a warning. `:+`
+/// statements add to a trigger and don't override anything.
+pub fn validate_custom(config: &KllConfig, documents: &[KllDocument]) -> Vec<CustomIssue> {
+    let custom = match &config.custom {
+        Some(custom) => custom,
+        None => return Vec::new(),
+    };
+
+    let animations = documents
+        .iter()
+        .flat_map(|d| d.sections.iter().flatten())
+        .filter_map(|s| match s {
+            Statement::AnimationSettings { name, .. } | Statement::AnimationFrame { name, .. } => {
+                Some(name)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut issues = Vec::new();
+    for (layer, block) in custom.iter() {
+        let layer = *layer;
+        let issue = |line: usize, message: String, error: bool| CustomIssue {
+            layer,
+            line,
+            message,
+            error,
+        };
+
+        let statements = match parse_statements(block) {
+            Ok(statements) => statements,
+            Err(e) => {
+                issues.push(issue(e.line, e.message, true));
+                continue;
+            }
+        };
+        let generated = match documents.get(layer) {
+            Some(document) => document,
+            None => {
+                issues.push(issue(
+                    0,
+                    format!("Layer {} is not generated, the block is ignored", layer),
+                    false,
+                ));
+                continue;
+            }
+        };
+        let remapped = |trigger: &str| {
+            generated.sections.iter().flatten().any(|s| match s {
+                Statement::Remap { trigger: t, .. } | Statement::Trigger { trigger: t, .. } => {
+                    split_schedule(t).0 == trigger
+                }
+                _ => false,
+            })
+        };
+
+        for s in statements {
+            match s.statement {
+                Statement::Assignment { name, .. } if HEADER_FIELDS.contains(&name.as_str()) => {
+                    issues.push(issue(
+                        s.line,
+                        format!("Redefines the {} header", name),
+                        true,
+                    ));
+                }
+                Statement::Remap { trigger, .. } => {
+                    let trigger = split_schedule(&trigger).0;
+                    if remapped(trigger) {
+                        issues.push(issue(
+                            s.line,
+                            format!("Overrides the generated binding for {}", trigger),
+                            false,
+                        ));
+                    }
+                }
+                Statement::AnimationSettings { name, .. }
+                | Statement::AnimationFrame { name, .. }
+                    if animations.contains(&&name) =>
+                {
+                    issues.push(issue(
+                        s.line,
+                        format!("Overrides the generated animation {}", name),
+                        false,
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+    issues
+}
@@ -1,5 +1,11 @@
+use crate::animation::*;
+use crate::ast::*;
+use crate::firmware::*;
+use crate::scancodes::*;
+
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 
@@ -7,8 +13,12 @@ use std::path::PathBuf;
 pub struct Animation {
     #[serde(rename = "type")]
     pub _type: Option<String>,
+    #[serde(default)]
     pub frames: Vec<String>,
     pub settings: String,
+    /// Generates the frames instead of listing them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generator: Option<Generator>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -34,19 +44,54 @@ pub struct KllHeader {
     pub other: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Led {
     pub id: usize,
     #[serde(rename = "scanCode")]
     pub scan_code: Option<String>,
     pub x: f32,
     pub y: f32,
+    /// Driver channels of the pixel (e.g. `0:8, 16:8, 32:8`), needed to map it to a scan code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<String>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct KeyAction {
     pub key: String,
     pub label: Option<String>,
+    /// Keys pressed together (e.g. `["LCTRL", "C"]`), sent instead of `key`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub combo: Option<Vec<String>>,
+    /// Combinations sent one after another (e.g. `[["LSHIFT", "H"], ["I"]]`), sent instead
+    /// of `key`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<Vec<Vec<String>>>,
+    /// Sent instead while the key is held longer than `holdTime`, the action is sent on tap
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hold: Option<String>,
+    /// Milliseconds before a press counts as a hold, defaults to `DEFAULT_HOLD_MS`
+    #[serde(rename = "holdTime", skip_serializing_if = "Option::is_none")]
+    pub hold_time: Option<u32>,
+    /// Sent when the key is released
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release: Option<String>,
+}
+
+impl KeyAction {
+    /// Whether the action needs state scheduling (KLL 0.5), which LTS firmware doesn't support
+    pub fn is_scheduled(&self) -> bool {
+        self.hold.is_some() || self.release.is_some()
+    }
+
+    /// The combinations sent by this action, a plain key is a single combination of one key
+    pub fn combos(&self) -> Vec<Vec<String>> {
+        match (&self.sequence, &self.combo) {
+            (Some(sequence), _) => sequence.clone(),
+            (None, Some(combo)) => vec![combo.clone()],
+            (None, None) => vec![vec![self.key.clone()]],
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -75,6 +120,9 @@ pub struct AnimationSetting {
     pub _type: String,
     pub default: serde_json::Value,
     pub values: Option<Vec<serde_json::Value>>,
+    /// User selected value, `default` is used when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -87,6 +135,9 @@ pub struct CannedAnimation {
     pub frames: Vec<String>,
     #[serde(rename = "custom-kll")]
     pub custom_kll: Option<String>,
+    /// Only enabled presets are added to the generated animations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -100,22 +151,39 @@ pub struct KllConfig {
     pub leds: Option<Vec<Led>>,
 }
 
+/// Hold time used when a key action doesn't set `holdTime`
+pub const DEFAULT_HOLD_MS: u32 = 200;
+
+/// A feature of the config that was dropped or rewritten for the firmware version
+#[derive(Clone, Debug, Serialize)]
+pub struct BuildWarning {
+    pub layer: Option<usize>,
+    /// Scan code of the key the warning is about
+    pub code: Option<String>,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UnmatchedKey {
+    /// Position in the matrix
+    pub index: usize,
+    pub code: String,
+    pub message: String,
+}
+
 pub struct KllFile {
     pub content: String,
     pub name: String,
 }
 
-fn layout_matrix(filename: &str) -> Vec<MatrixKey> {
+fn layout_config(filename: &str) -> KllConfig {
     println!("Reading {}", filename);
-    let json: KllConfig = {
-        let contents = fs::read_to_string(filename).expect("Missing layout");
-        serde_json::from_str(&contents).unwrap()
-    };
-    json.matrix
+    let contents = fs::read_to_string(filename).expect("Missing layout");
+    serde_json::from_str(&contents).unwrap()
 }
 
 fn crop_str(s: &str, pos: usize) -> &str {
-    match s.char_indices().skip(pos).next() {
+    match s.char_indices().nth(pos) {
         Some((pos, _)) => &s[pos..],
         None => "",
     }
@@ -124,7 +192,9 @@ fn crop_str(s: &str, pos: usize) -> &str {
 pub fn kll_filename(filename: String) -> String {
     let mut path = PathBuf::from(filename);
     path.set_extension(""); // Remove .kll extension
-    path.into_os_string().into_string().unwrap_or("".to_string())
+    path.into_os_string()
+        .into_string()
+        .unwrap_or("".to_string())
 }
 
 pub fn kll_layer(filenames: Vec<String>) -> String {
@@ -141,99 +211,404 @@ pub fn kll_list(layers: Vec<String>) -> String {
 
 pub fn format_key(s: &str) -> String {
     if s.starts_with("CONS:") {
-        return format!("CONS\"{}\"", crop_str(s, 5));
+        format!("CONS\"{}\"", crop_str(s, 5))
     } else if s.starts_with("SYS:") {
-        return format!("SYS\"{}\"", crop_str(s, 4));
+        format!("SYS\"{}\"", crop_str(s, 4))
     } else {
-        return format!("U\"{}\"", s);
+        format!("U\"{}\"", s)
+    }
+}
+
+/// Formats a single key of a result, `#:` keys are used as-is
+fn format_result(k: &str) -> String {
+    match k.strip_prefix("#:") {
+        Some(result) => result.to_string(),
+        None => format_key(k),
+    }
+}
+
+/// Formats combinations (`U"LCTRL" + U"C"`) and sequences of them (`U"A", U"B"`)
+pub fn format_combos(combos: &[Vec<String>]) -> String {
+    combos
+        .iter()
+        .map(|combo| {
+            combo
+                .iter()
+                .map(|k| format_result(k))
+                .collect::<Vec<_>>()
+                .join(" + ")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Keys using features the firmware version can't build
+pub fn unsupported_actions(config: &KllConfig, firmware: &FirmwareProfile) -> Vec<String> {
+    if firmware.scheduling {
+        return Vec::new();
+    }
+    config
+        .matrix
+        .iter()
+        .flat_map(|key| {
+            key.layers
+                .iter()
+                .filter(|(_, action)| action.is_scheduled())
+                .map(move |(l, _)| {
+                    format!(
+                        "{} (layer {}): hold/release actions need KLL 0.5, {} uses KLL {}",
+                        key.code, l, firmware.version, firmware.kll
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Pixel to scan code mappings and physical positions (in mm) of the LEDs. Pixels without
+/// `channels` or a scan code only get a position.
+pub fn led_statements(leds: &[Led]) -> Vec<Statement> {
+    let mut statements = Vec::new();
+    for led in leds.iter() {
+        if let (Some(channels), Some(scan_code)) = (&led.channels, &led.scan_code) {
+            statements.push(Statement::PixelMap {
+                index: led.id,
+                channels: channels.clone(),
+                scan_code: match scan_code.strip_prefix('S') {
+                    Some(_) => scan_code.clone(),
+                    None => format!("S{}", scan_code),
+                },
+            });
+        }
+        statements.push(Statement::PixelPosition {
+            index: led.id,
+            position: format!("x:{}, y:{}", led.x, led.y),
+        });
+    }
+    statements
+}
+
+/// The value chosen for a configurable setting. Select values may be given either by their
+/// `name` or their `value`, anything not listed in `values` is rejected.
+fn setting_value(setting: &AnimationSetting) -> Result<serde_json::Value, String> {
+    let chosen = setting.value.as_ref().unwrap_or(&setting.default);
+
+    match &setting.values {
+        Some(values) => values
+            .iter()
+            .find_map(|v| match v.get("value") {
+                Some(value) if v.get("name") == Some(chosen) || value == chosen => Some(value),
+                Some(_) => None,
+                None if v == chosen => Some(v),
+                None => None,
+            })
+            .cloned()
+            .ok_or_else(|| format!("{} is not a valid value for {}", chosen, setting.name)),
+        None => Ok(chosen.clone()),
+    }
+}
+
+/// A `{"r", "g", "b"}` colour setting
+fn setting_color(value: &serde_json::Value) -> Option<[f64; 3]> {
+    let channel = |c: &str| value.get(c)?.as_f64();
+    Some([channel("r")?, channel("g")?, channel("b")?])
+}
+
+/// A colour as the channels of a pixel, e.g. `255,0,0`
+fn format_color(color: [f64; 3]) -> String {
+    let [r, g, b] = color.map(|c| c.clamp(0.0, 255.0).round() as u8);
+    format!("{},{},{}", r, g, b)
+}
+
+/// Expands the contents of a `${...}` placeholder:
+/// - `name`: a setting, colours become `r,g,b`
+/// - `name!amount`: a colour faded `amount` (0-1) of the way to black
+/// - `from:to:amount`: a colour blended `amount` of the way from one colour to another
+/// - `__NAME__`: the name of the animation
+fn expand_placeholder(
+    placeholder: &str,
+    name: &str,
+    values: &IndexMap<String, serde_json::Value>,
+) -> Result<String, String> {
+    if placeholder == "__NAME__" {
+        return Ok(name.to_string());
+    }
+    let value = |setting: &str| {
+        values
+            .get(setting)
+            .ok_or_else(|| format!("${{{}}} refers to unknown setting {}", placeholder, setting))
+    };
+    let color = |setting: &str| {
+        setting_color(value(setting)?).ok_or_else(|| format!("{} is not a color", setting))
+    };
+    let amount = |amount: &str| {
+        amount
+            .parse::<f64>()
+            .ok()
+            .filter(|a| (0.0..=1.0).contains(a))
+            .ok_or_else(|| format!("Invalid amount in ${{{}}}", placeholder))
+    };
+
+    if let Some((setting, fade)) = placeholder.split_once('!') {
+        let (color, fade) = (color(setting)?, amount(fade)?);
+        return Ok(format_color(color.map(|c| c * (1.0 - fade))));
+    }
+    if let [from, to, blend] = placeholder.split(':').collect::<Vec<_>>()[..] {
+        let (from, to, blend) = (color(from)?, color(to)?, amount(blend)?);
+        return Ok(format_color(
+            [0, 1, 2].map(|i| from[i] + (to[i] - from[i]) * blend),
+        ));
+    }
+
+    let value = value(placeholder)?;
+    match value {
+        serde_json::Value::String(s) => Ok(s.clone()),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        v => setting_color(v)
+            .map(format_color)
+            .ok_or_else(|| format!("{} can't be used in KLL", v)),
+    }
+}
+
+/// Expands a canned animation named `name` into a regular animation by substituting `${...}`
+/// placeholders (see `expand_placeholder`) in its settings, frames and custom KLL. Returns the
+/// animation and the expanded custom KLL.
+pub fn expand_canned(
+    name: &str,
+    canned: &CannedAnimation,
+) -> Result<(Animation, Option<String>), String> {
+    let mut values = IndexMap::new();
+    for setting in canned.configurable.iter() {
+        values.insert(setting.name.clone(), setting_value(setting)?);
+    }
+    let expand = |s: &str| -> Result<String, String> {
+        let mut expanded = String::new();
+        let mut rest = s;
+        while let Some((start, end)) = rest
+            .find("${")
+            .and_then(|start| Some((start, start + rest[start..].find('}')?)))
+        {
+            expanded.push_str(&rest[..start]);
+            expanded.push_str(&expand_placeholder(&rest[start + 2..end], name, &values)?);
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+        // Anything left over would be passed to the KLL compiler as-is
+        if expanded.contains("${") || expanded.contains('{') {
+            return Err(format!("Could not expand {}", expanded));
+        }
+        Ok(expanded)
+    };
+
+    let animation = Animation {
+        _type: Some(canned._type.clone()),
+        frames: canned
+            .frames
+            .iter()
+            .map(|f| expand(f))
+            .collect::<Result<_, _>>()?,
+        settings: expand(&canned.settings)?,
+        generator: None,
+    };
+    let custom_kll = canned.custom_kll.as_deref().map(expand).transpose()?;
+    Ok((animation, custom_kll))
+}
+
+/// Checks that every enabled canned animation can be expanded
+pub fn validate_canned(config: &KllConfig) -> Vec<String> {
+    config
+        .canned
+        .iter()
+        .flatten()
+        .filter(|(_, c)| c.enabled == Some(true))
+        .filter_map(|(k, c)| expand_canned(k, c).err().map(|e| format!("{}: {}", k, e)))
+        .collect()
+}
+
+/// Formats the result of a trigger (`:+`) statement. Plain names/numbers are expanded based
+/// on the trigger type, anything that already looks like a KLL result is used as-is.
+pub fn format_trigger(t: &Trigger) -> String {
+    let action = t.action.trim();
+    if action.contains('[') || action.contains('(') || action.contains('"') {
+        return action.to_string();
+    }
+
+    match t._type.to_lowercase().as_ref() {
+        "animation" => format!("A[{}]", action),
+        "layer" => format!("Layer[{}]", action),
+        "layershift" => format!("LayerShift[{}]", action),
+        "layerlock" => format!("LayerLock[{}]", action),
+        "layerlatch" => format!("LayerLatch[{}]", action),
+        _ => action.to_string(),
+    }
+}
+
+fn push_triggers<T: Clone>(
+    layers: &mut Vec<Vec<T>>,
+    triggers: &mut Vec<Vec<(String, Vec<Trigger>)>>,
+    base_key: &str,
+    key_triggers: &IndexMap<usize, Trigger>,
+) {
+    for (t, trigger) in key_triggers {
+        let t = *t;
+        if triggers.len() <= t {
+            triggers.resize(t + 1, Vec::new());
+        }
+        // Make sure a file is generated for layers that only have triggers
+        if layers.len() <= t {
+            layers.resize(t + 1, Vec::new());
+        }
+        triggers[t].push((base_key.to_string(), vec![trigger.clone()]));
+    }
+}
+
+/// The scan code map of the firmware version and the base layout the generated files are
+/// applied on top of
+fn base_layout(
+    name: &str,
+    base: &str,
+    firmware: &FirmwareProfile,
+) -> (Option<&'static ScanCodeMap>, KllConfig) {
+    // Older firmware versions may have a different default map, see SCAN_CODE_MAPS
+    let firmware_map = scan_code_map(name, firmware.scan_codes);
+    let base_layout = firmware_map.and_then(|m| m.base).unwrap_or(base);
+    let base = layout_config(&format!("./layouts/{}-{}.json", name, base_layout));
+    (firmware_map, base)
+}
+
+/// The layer 0 key of each key's counterpart in the base layout, matched by scan code.
+/// Split boards repeat scan codes for each half, the n-th key with a scan code matches the
+/// n-th base key with that code.
+fn match_base_keys<'a>(
+    matrix: &[MatrixKey],
+    default: &'a [MatrixKey],
+    firmware_map: Option<&ScanCodeMap>,
+) -> Vec<Option<&'a str>> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    matrix
+        .iter()
+        .map(|key| {
+            let code = firmware_map.map_or(key.code.clone(), |m| m.to_firmware(&key.code));
+            let n = seen.entry(code.to_lowercase()).or_insert(0);
+            let def_key = default
+                .iter()
+                .filter(|def_key| def_key.code.eq_ignore_ascii_case(&code))
+                .nth(*n);
+            *n += 1;
+            def_key
+                .and_then(|k| k.layers.get(&0))
+                .map(|a| a.key.as_str())
+        })
+        .collect()
+}
+
+/// Keys that have no counterpart in the base layout and can't be generated. Bases of older
+/// firmware (see SCAN_CODE_MAPS) may lack keys of current layouts, those keys are skipped
+/// with a warning by generate_kll_documents instead.
+pub fn unmatched_keys(config: &KllConfig, firmware: &FirmwareProfile) -> Vec<UnmatchedKey> {
+    let name = config.header.name.replace(" ", "_");
+    if name.is_empty() {
+        return Vec::new();
     }
+
+    let (firmware_map, base) = base_layout(&name, &config.header.base, firmware);
+    if firmware_map.is_some() {
+        return Vec::new();
+    }
+    match_base_keys(&config.matrix, &base.matrix, firmware_map)
+        .iter()
+        .zip(config.matrix.iter())
+        .enumerate()
+        .filter(|(_, (base_key, _))| base_key.is_none())
+        .map(|(index, (_, key))| UnmatchedKey {
+            index,
+            code: key.code.clone(),
+            message: format!(
+                "{} is not in the {} base layout",
+                key.code, base.header.layout
+            ),
+        })
+        .collect()
 }
 
-pub fn generate_kll(config: &KllConfig, is_lts: bool) -> Vec<KllFile> {
+/// Builds the statements of each generated .kll layer file, see `generate_kll`. Features
+/// the firmware doesn't support are dropped or rewritten and reported as warnings.
+pub fn generate_kll_documents(
+    config: &KllConfig,
+    firmware: &FirmwareProfile,
+    changed_only: bool,
+) -> (Vec<KllDocument>, Vec<BuildWarning>) {
     let header = config.header.clone();
     let name = &header.name.replace(" ", "_"); //sanitize
     let variant = header.variant.unwrap_or("".to_string()).replace(" ", "_");
     let layout = header.layout.clone();
-    let base_layout = &header.base;
 
-    let mut files = Vec::new();
     if name.is_empty() || layout.is_empty() {
         // Invalid Header Information
-        return files;
+        return (Vec::new(), Vec::new());
     }
 
-    let mut default = layout_matrix(&format!("./layouts/{}-{}.json", name, base_layout));
-
-    let mut layers: Vec<Vec<(String, String)>> = Vec::new();
-    let triggers: Vec<Vec<(String, Vec<Trigger>)>> = Vec::new();
-
-    // Find the differences between the default map and the user's map
-    match name.to_lowercase().as_ref() {
-        // WhiteFox layouts have fewer keys than the defaultMap so we need to verify based
-        //  upon the scan codes rather than just a sequence. Long term this method should
-        //  probably be the preferred method for building up layer files
-        "whitefox" => {
-            if is_lts {
-                // Between LTS and Latest the scancode mapping for White Fox changed. Previously
-                //  there was a single all encompassing map, now there are a number of smaller
-                //  ones that have different (sensible) default scancode mappings. This causes
-                //  a little bit of havok due to the way layering works, we override what was
-                //  previously there, we'll look for a special `.lts.json` file here.
-                let lts_base_name = format!("./layouts/{}-{}.lts.json", name, base_layout);
-                default = layout_matrix(&lts_base_name);
-            }
+    let (firmware_map, base) = base_layout(name, &header.base, firmware);
 
-            for (_i, key) in config.matrix.iter().enumerate() {
-                // First find the corresponding key via scan code
-                let idx_in_def = default.iter().position(|def_key| key.code == def_key.code);
-
-                if let Some(idx_in_def) = idx_in_def {
-                    for (l, layer) in key.layers.iter() {
-                        let l = *l;
-                        if !layers.get(l).is_some() {
-                            layers.resize(l + 1, Vec::new());
-                        }
-                        layers[l].push((
-                            default[idx_in_def].layers.get(&0).unwrap().key.clone(),
-                            layer.key.clone(),
-                        ));
-                    }
+    let mut layers: Vec<Vec<(&str, String, KeyAction)>> = Vec::new();
+    let mut triggers: Vec<Vec<(String, Vec<Trigger>)>> = Vec::new();
+    let mut warnings = Vec::new();
+    let warning = |layer: Option<usize>, code: Option<&str>, message: String| BuildWarning {
+        layer,
+        code: code.map(|c| c.to_string()),
+        message,
+    };
 
-                    // Process "trigger" entries
-                    if !is_lts {
-                        if let Some(ts) = &key.triggers {
-                            for (_t, _trigger) in ts {
-                                // TODO
-                                //triggers[t][&default[idx_in_def].layers.get(&0).unwrap().key].push(trigger);
-                            }
-                        }
-                    }
+    // Find the differences between the default map and the user's map, keys without a
+    //  counterpart in the base layout are reported by unmatched_keys
+    let base_keys = match_base_keys(&config.matrix, &base.matrix, firmware_map);
+    for (key, base_key) in config.matrix.iter().zip(base_keys) {
+        let base_key = match base_key {
+            Some(base_key) => base_key,
+            None => {
+                if firmware_map.is_some() {
+                    warnings.push(warning(
+                        None,
+                        Some(&key.code),
+                        format!(
+                            "{} is not in the {} base layout of {} and was skipped",
+                            key.code, base.header.layout, firmware.version
+                        ),
+                    ));
                 }
+                continue;
             }
+        };
+
+        // Process "layer" entries
+        for (l, layer) in key.layers.iter() {
+            let l = *l;
+            if layers.get(l).is_none() {
+                layers.resize(l + 1, Vec::new());
+            }
+            let combos = layer.combos();
+            let unbound = combos.iter().flatten().all(|k| k.is_empty()) && !layer.is_scheduled();
+            let unchanged = l == 0 && combos == [[base_key]] && !layer.is_scheduled();
+            if changed_only && (unbound || unchanged) {
+                continue;
+            }
+            layers[l].push((&key.code, base_key.to_string(), layer.clone()));
         }
-        _ => {
-            for (i, key) in config.matrix.iter().enumerate() {
-                // TODO: Dedup with ergodox
-                // Process "layer" entries
-                for (l, layer) in key.layers.iter() {
-                    let l = *l;
-                    if !layers.get(l).is_some() {
-                        layers.resize(l + 1, Vec::new());
-                    }
-                    layers[l].push((
-                        default[i].layers.get(&0).unwrap().key.clone(),
-                        layer.key.clone(),
-                    ));
-                }
 
-                // Process "trigger" entries
-                if !is_lts {
-                    if let Some(ts) = &key.triggers {
-                        for (_t, _trigger) in ts {
-                            // TODO
-                            //triggers[t][&default[i].layers.get(&0).unwrap().key].push(trigger);
-                        }
-                    }
+        // Process "trigger" entries
+        if let Some(ts) = &key.triggers {
+            if firmware.triggers {
+                push_triggers(&mut layers, &mut triggers, base_key, ts);
+            } else {
+                for (t, trigger) in ts.iter() {
+                    warnings.push(warning(
+                        Some(*t),
+                        Some(&key.code),
+                        format!(
+                            "Trigger {} is not supported by {} and was dropped",
+                            format_trigger(trigger),
+                            firmware.version
+                        ),
+                    ));
                 }
             }
         }
@@ -251,18 +626,18 @@ pub fn generate_kll(config: &KllConfig, is_lts: bool) -> Vec<KllFile> {
     headers.insert("Generator".to_string(), header.generator);
 
     let header = headers
+        .into_iter()
+        .map(|(name, value)| Statement::Assignment { name, value })
+        .collect::<Vec<_>>();
+    let defines = config
+        .defines
         .iter()
-        .map(|(k, v)| format!("{} = \"{}\";", k, v))
-        .collect::<Vec<_>>()
-        .join("\n");
-    let defines = match &config.defines {
-        Some(d) => d
-            .iter()
-            .map(|define| format!("{} = \"{}\";", define.name, define.value))
-            .collect::<Vec<_>>()
-            .join("\n"),
-        None => "".to_string(),
-    };
+        .flatten()
+        .map(|define| Statement::Assignment {
+            name: define.name.clone(),
+            value: define.value.clone(),
+        })
+        .collect::<Vec<_>>();
 
     //let mut file_args = Vec::new();
     let _controller_ver =
@@ -270,49 +645,138 @@ pub fn generate_kll(config: &KllConfig, is_lts: bool) -> Vec<KllFile> {
                                                                                             // let hashbaby = "";
     let layout_name = format!("{}-{}", name, layout);
 
-    let mut animations = "".to_string();
-    let mut ignored_animations = Vec::new();
-    if !is_lts {
-        match &config.animations {
-            Some(a) => {
-                animations = a
-                    .iter()
-                    .map(|(k, v)| {
-                        let mut s = format!("A[{}] <= {};\n", k, v.settings);
-
-                        let mut i = 1; // TODO: Use enumerate here
-                        for frame in v.frames.iter() {
-                            if frame.starts_with("#") {
-                                s.push_str(&format!("{}\n", frame));
-                            } else {
-                                s.push_str(&format!("A[{}, {}] <= {};\n", k, i, frame));
-                                i += 1;
-                            }
-                        }
-                        if i > 1 {
-                            return s;
-                        } else {
-                            ignored_animations.push(k);
-                            return format!("### {} is empty, skipping", k);
-                        }
-                    })
-                    .collect::<Vec<_>>()
-                    .join("\n");
+    let mut animations = Vec::new();
+    if firmware.animations {
+        let mut all_animations = config.animations.clone().unwrap_or_default();
+        let mut canned_kll = IndexMap::new();
+        // Enabled canned animations are added as regular animations, invalid settings are
+        //  rejected by validate_canned before building
+        for (k, canned) in config.canned.iter().flatten() {
+            if canned.enabled != Some(true) || all_animations.contains_key(k) {
+                continue;
+            }
+            if let Ok((animation, custom_kll)) = expand_canned(k, canned) {
+                all_animations.insert(k.clone(), animation);
+                if let Some(custom_kll) = custom_kll {
+                    canned_kll.insert(k.clone(), custom_kll);
+                }
             }
-            _ => {}
+        }
+
+        for (k, v) in all_animations.iter() {
+            let mut s = vec![Statement::AnimationSettings {
+                name: k.clone(),
+                settings: v.settings.clone(),
+            }];
+
+            let leds = config.leds.as_ref().or(base.leds.as_ref());
+            let frames = match &v.generator {
+                Some(generator) => generate_frames(generator, leds.map_or(&[], |l| l)),
+                None => v.frames.clone(),
+            };
+
+            let mut i = 1; // TODO: Use enumerate here
+            for frame in frames.iter() {
+                if let Some(comment) = frame.strip_prefix('#') {
+                    s.push(Statement::Comment(comment.to_string()));
+                } else {
+                    s.push(Statement::AnimationFrame {
+                        name: k.clone(),
+                        index: i,
+                        frame: frame.clone(),
+                    });
+                    i += 1;
+                }
+            }
+            if let Some(custom_kll) = canned_kll.get(k) {
+                s.push(Statement::Raw(custom_kll.clone()));
+            }
+            // A ripple from a key is started by pressing it
+            if let Some(Generator::Ripple { key: Some(key), .. }) = &v.generator {
+                if leds.and_then(|l| led_column(l, key)).is_none() {
+                    warnings.push(warning(
+                        Some(0),
+                        Some(key),
+                        format!("Key {} has no LED, {} starts in the middle", key, k),
+                    ));
+                }
+                if firmware.triggers {
+                    let code = firmware_map.map_or(key.clone(), |m| m.to_firmware(key));
+                    s.push(Statement::Trigger {
+                        trigger: format!("S{}", code),
+                        result: format!("A[{}]", k),
+                    });
+                }
+            }
+            if i > 1 {
+                animations.append(&mut s);
+                animations.push(Statement::Blank);
+            } else {
+                animations.push(Statement::Comment(format!("## {} is empty, skipping", k)));
+                warnings.push(warning(
+                    Some(0),
+                    None,
+                    format!("Animation {} has no frames and was skipped", k),
+                ));
+            }
+        }
+    } else {
+        let canned = config.canned.iter().flatten();
+        let dropped = config.animations.iter().flatten().map(|(k, _)| k).chain(
+            canned
+                .filter(|(_, c)| c.enabled == Some(true))
+                .map(|(k, _)| k),
+        );
+        for k in dropped {
+            warnings.push(warning(
+                Some(0),
+                None,
+                format!(
+                    "Animation {} is not supported by {} and was dropped",
+                    k, firmware.version
+                ),
+            ));
         }
     }
 
+    // The firmware already defines the LEDs of the base layout, only modified geometry is emitted
+    let pixels = match &config.leds {
+        Some(leds) if Some(leds) != base.leds.as_ref() => {
+            if firmware.leds {
+                led_statements(leds)
+            } else {
+                warnings.push(warning(
+                    Some(0),
+                    None,
+                    format!(
+                        "LED geometry is not supported by {} and was dropped",
+                        firmware.version
+                    ),
+                ));
+                Vec::new()
+            }
+        }
+        _ => Vec::new(),
+    };
+
     // Generate .kll files
+    let mut documents = Vec::new();
     for (n, layer) in layers.iter().enumerate() {
-        let out = layer
+        let remaps = layer
             .iter()
-            .map(|(k, v)| {
+            .flat_map(|(code, k, action)| {
                 let mut comment_out = false;
                 let k = format_key(k);
+                let v = &action.key;
                 let mut s = v.to_string();
-                if v.starts_with("#:") {
-                    if is_lts && v.contains("ledControl") {
+                if action.combo.is_some() || action.sequence.is_some() {
+                    s = format_combos(&action.combos());
+                } else if v.starts_with("#:") {
+                    if !firmware.supports_result(v) {
+                        comment_out = true;
+                    } else if firmware.led_control == LedControl::ModeAmountIndex
+                        && v.contains("ledControl")
+                    {
                         let m = v.replace(" ", "");
                         if m.contains("ledControl(0,15)") {
                             // LED-
@@ -326,8 +790,18 @@ pub fn generate_kll(config: &KllConfig, is_lts: bool) -> Vec<KllFile> {
                         } else {
                             comment_out = true;
                         }
-                    } else if is_lts && v.contains("animation_control") {
-                        comment_out = true;
+                        if !comment_out {
+                            warnings.push(warning(
+                                Some(n),
+                                Some(code),
+                                format!(
+                                    "{} was rewritten to {} for {}",
+                                    crop_str(v, 2),
+                                    s,
+                                    firmware.version
+                                ),
+                            ));
+                        }
                     } else {
                         s = crop_str(v, 2).to_string();
                     }
@@ -336,53 +810,119 @@ pub fn generate_kll(config: &KllConfig, is_lts: bool) -> Vec<KllFile> {
                 }
 
                 if comment_out {
-                    format!("#{} : {};", k, s)
-                } else {
-                    format!("{} : {};", k, s)
+                    warnings.push(warning(
+                        Some(n),
+                        Some(code),
+                        format!(
+                            "{} is not supported by {} and was commented out",
+                            crop_str(v, 2),
+                            firmware.version
+                        ),
+                    ));
+                    return vec![Statement::Disabled(Box::new(Statement::Remap {
+                        trigger: k,
+                        result: s,
+                    }))];
+                }
+
+                // Scheduled actions are rejected by unsupported_actions when not supported
+                let mut statements = Vec::new();
+                match action.hold.as_ref().filter(|_| firmware.scheduling) {
+                    Some(hold) => {
+                        // Tap when released before the hold time
+                        let t = action.hold_time.unwrap_or(DEFAULT_HOLD_MS);
+                        statements.push(Statement::Remap {
+                            trigger: format!("{}(H:{}ms)", k, t),
+                            result: format_result(hold),
+                        });
+                        statements.push(Statement::Remap {
+                            trigger: format!("{}(R:{}ms)", k, t),
+                            result: s,
+                        });
+                    }
+                    None => statements.push(Statement::Remap {
+                        trigger: k.clone(),
+                        result: s,
+                    }),
                 }
+                if let Some(release) = action.release.as_ref().filter(|_| firmware.scheduling) {
+                    statements.push(Statement::Remap {
+                        trigger: format!("{}(R)", k),
+                        result: format_result(release),
+                    });
+                }
+                statements
             })
-            .collect::<Vec<_>>()
-            .join("\n");
+            .collect::<Vec<_>>();
 
-        let mut triggers_out = "".to_string();
-        if let Some(triggers) = triggers.get(n) {
-            triggers_out = triggers
-                .iter()
-                .map(|(k, v)| {
-                    let k = format_key(k);
-                    v.iter()
-                        .map(|t| format!("{} :+ {};", k, t.action))
-                        .collect::<Vec<_>>()
-                        .join("\n")
+        let triggers_out = triggers
+            .get(n)
+            .into_iter()
+            .flatten()
+            .flat_map(|(k, v)| {
+                let k = format_key(k);
+                v.iter().map(move |t| Statement::Trigger {
+                    trigger: k.clone(),
+                    result: format_trigger(t),
                 })
-                .collect::<Vec<_>>()
-                .join("\n");
-        }
-
-        let custom = match &config.custom {
-            Some(custom) => match custom.get(&n) {
-                Some(c) => format!("\n\n{}", c),
-                None => "".to_string(),
-            },
-            None => "".to_string(),
-        };
+            })
+            .collect::<Vec<_>>();
 
-        let out = {
-            if n == 0 {
-                format!(
-                    "{}\n\n{}\n\n{}\n\n{}{}\n\n{}\n\n",
-                    header, defines, out, triggers_out, custom, animations
-                )
-            } else {
-                format!("{}\n\n{}\n\n{}{}\n\n", header, out, triggers_out, custom)
+        let mut sections = vec![header.clone()];
+        if n == 0 {
+            sections.push(defines.clone());
+            if !pixels.is_empty() {
+                sections.push(pixels.clone());
             }
-        };
+        }
+        sections.push(remaps);
+        sections.push(triggers_out);
+        if let Some(c) = config.custom.as_ref().and_then(|custom| custom.get(&n)) {
+            sections.push(vec![Statement::Raw(c.clone())]);
+        }
+        if n == 0 {
+            sections.push(animations.clone());
+        }
 
-        files.push(KllFile {
-            content: out,
+        documents.push(KllDocument {
             name: format!("{}-{}.kll", layout_name, n),
+            sections,
         });
     }
 
-    return files;
+    (documents, warnings)
+}
+
+/// Generates a .kll file per layer, along with warnings for anything the firmware doesn't
+/// support. With `changed_only` layer 0 only contains the keys that differ from the base
+/// layout and unbound keys are left out of every layer.
+#[allow(dead_code)]
+pub fn generate_kll_with_warnings(
+    config: &KllConfig,
+    firmware: &FirmwareProfile,
+    changed_only: bool,
+) -> (Vec<KllFile>, Vec<BuildWarning>) {
+    let (documents, warnings) = generate_kll_documents(config, firmware, changed_only);
+    (kll_files(documents), warnings)
+}
+
+/// Prints generated documents into .kll files
+pub fn kll_files(documents: Vec<KllDocument>) -> Vec<KllFile> {
+    documents
+        .into_iter()
+        .map(|doc| KllFile {
+            content: doc.to_string(),
+            name: doc.name,
+        })
+        .collect()
+}
+
+/// Generates a .kll file per layer, see `generate_kll_with_warnings`
+#[allow(dead_code)]
+pub fn generate_kll(
+    config: &KllConfig,
+    firmware: &FirmwareProfile,
+    changed_only: bool,
+) -> Vec<KllFile> {
+    generate_kll_with_warnings(config, firmware, changed_only).0
 }
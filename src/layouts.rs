@@ -0,0 +1,123 @@
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+
+pub const LAYOUT_DIR: &str = "./layouts";
+
+/// A layout file as of a commit
+#[derive(Clone)]
+pub struct CachedLayout {
+    /// Quoted git blob id of the file
+    pub etag: String,
+    pub content: Arc<String>,
+}
+
+/// Whether a requested layout name stays inside LAYOUT_DIR
+pub fn is_layout_name(file: &str) -> bool {
+    !file.is_empty() && !file.contains(['/', '\\']) && !file.contains("..")
+}
+
+/// Resolves a revision to a commit id, `None` if it does not exist
+pub fn resolve_rev(rev: &str) -> Result<Option<String>, StatusCode> {
+    let result = Command::new("git")
+        .args([
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{}^{{commit}}", rev),
+        ])
+        .output()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !result.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(
+        String::from_utf8_lossy(&result.stdout).trim().to_string(),
+    ))
+}
+
+/// The `commit:path` of a layout at a revision, symlinked layouts resolve to their target.
+/// Also returns the commit.
+pub fn layout_key(file: &str, rev: &str) -> Result<(String, String), StatusCode> {
+    if !is_layout_name(file) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let commit = resolve_rev(rev)?.ok_or(StatusCode::NOT_FOUND)?;
+
+    let path = PathBuf::from(format!("{}/{}", LAYOUT_DIR, file));
+    let realfile = fs::read_link(&path).unwrap_or(PathBuf::from(&file));
+    let realpath = format!("{}/{}", LAYOUT_DIR, realfile.to_str().unwrap());
+
+    Ok((format!("{}:{}", commit, realpath), commit))
+}
+
+/// Reads a layout from git, see `layout_key`
+pub fn read_layout(key: &str) -> Result<CachedLayout, StatusCode> {
+    let git = |args: &[&str]| -> Result<Vec<u8>, StatusCode> {
+        let result = Command::new("git")
+            .args(args)
+            .output()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if !result.status.success() {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(result.stdout)
+    };
+
+    let blob = git(&["rev-parse", "--verify", "--quiet", key])?;
+    let blob = String::from_utf8_lossy(&blob).trim().to_string();
+    let content = git(&["cat-file", "blob", &blob])?;
+    Ok(CachedLayout {
+        etag: format!("\"{}\"", blob),
+        content: Arc::new(String::from_utf8_lossy(&content).to_string()),
+    })
+}
+
+/// Only an explicit commit id always refers to the same contents, see `resolve_rev`
+pub fn is_pinned(commit: &str, rev: &str) -> bool {
+    commit.eq_ignore_ascii_case(rev)
+}
+
+/// Serves a layout with its ETag, or `304 Not Modified` when the request's `If-None-Match`
+/// already has it. Pinned layouts (see `is_pinned`) may be cached forever.
+pub fn layout_response(layout: &CachedLayout, pinned: bool, headers: &HeaderMap) -> Response {
+    let cache_control = if pinned {
+        "public, max-age=31536000, immutable"
+    } else {
+        "public, no-cache"
+    };
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| {
+            h.split(',')
+                .any(|t| t.trim() == layout.etag || t.trim() == "*")
+        });
+    if not_modified {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [
+                (header::ETAG, layout.etag.clone()),
+                (header::CACHE_CONTROL, cache_control.to_string()),
+            ],
+        )
+            .into_response();
+    }
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::ETAG, layout.etag.clone()),
+            (header::CACHE_CONTROL, cache_control.to_string()),
+        ],
+        layout.content.to_string(),
+    )
+        .into_response()
+}
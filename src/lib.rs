@@ -1,3 +1,14 @@
+pub mod animation;
+pub mod ast;
 pub mod build;
+pub mod firmware;
+pub mod hid;
+pub mod import;
 pub mod kll;
+pub mod layouts;
+pub mod limits;
+pub mod merge;
+pub mod preview;
+pub mod render;
+pub mod scancodes;
 pub mod versions;
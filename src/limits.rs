@@ -0,0 +1,212 @@
+use crate::ast::*;
+use crate::firmware::FirmwareProfile;
+
+use serde::Serialize;
+use std::fmt::Write;
+
+/// Resources a keyboard's microcontroller leaves for the generated keymap.
+///
+/// Chip flash and RAM sizes are from the NXP K20 (MK20DX128VLF5, MK20DX256VLH7) and Microchip
+/// SAM4S datasheets. The space taken by the bootloader and firmware, and so what's left for
+/// the keymap, is an estimate: it varies with the build options of each keyboard.
+pub struct BoardLimits {
+    /// Keyboard names, as in the layout header (lowercase)
+    pub boards: &'static [&'static str],
+    /// Controller versions (`FirmwareProfile::version`) these limits apply to, any if empty
+    pub versions: &'static [&'static str],
+    /// Flash left after the bootloader and firmware, in bytes (estimate)
+    pub flash: usize,
+    pub max_layers: usize,
+    /// Frames of all animations together, they're kept in RAM while playing (estimate)
+    pub max_frames: usize,
+}
+
+/// Searched in order, the first entry matching both the keyboard and version is used
+pub const BOARD_LIMITS: &[BoardLimits] = &[
+    // MK20DX128VLF5: 128 KB flash, 16 KB RAM. LTS firmware has no LED or animation support,
+    // leaving about half the flash for the keymap.
+    BoardLimits {
+        boards: &["md1", "infinity"],
+        versions: &["v0.5.0"],
+        flash: 64 * 1024,
+        max_layers: 8,
+        max_frames: 0,
+    },
+    BoardLimits {
+        boards: &["md1", "infinity"],
+        versions: &[],
+        flash: 32 * 1024,
+        max_layers: 8,
+        max_frames: 64,
+    },
+    // MK20DX256VLH7: 256 KB flash, 64 KB RAM
+    BoardLimits {
+        boards: &["md1.1", "mdergo1", "whitefox", "ktype", "k-type"],
+        versions: &["v0.5.0"],
+        flash: 160 * 1024,
+        max_layers: 16,
+        max_frames: 0,
+    },
+    BoardLimits {
+        boards: &["md1.1", "mdergo1", "whitefox", "ktype", "k-type"],
+        versions: &[],
+        flash: 96 * 1024,
+        max_layers: 16,
+        max_frames: 512,
+    },
+    // SAM4S8: 512 KB flash, 128 KB RAM
+    BoardLimits {
+        boards: &[
+            "kira",
+            "gemini",
+            "geminidusk",
+            "geminidawn",
+            "geminiduskdawn",
+        ],
+        versions: &[],
+        flash: 256 * 1024,
+        max_layers: 16,
+        max_frames: 1024,
+    },
+];
+
+/// Used for keyboards and versions missing from `BOARD_LIMITS` (e.g. ICPad, ErgoDox), as
+/// small as the smallest chip we support
+pub const DEFAULT_LIMITS: BoardLimits = BoardLimits {
+    boards: &[],
+    versions: &[],
+    flash: 32 * 1024,
+    max_layers: 8,
+    max_frames: 64,
+};
+
+/// Estimated flash use of the generated statements, in bytes
+const LAYER_BYTES: usize = 64;
+const BINDING_BYTES: usize = 16;
+const ANIMATION_BYTES: usize = 16;
+const FRAME_BYTES: usize = 4;
+const FRAME_PIXEL_BYTES: usize = 8;
+const PIXEL_BYTES: usize = 8;
+
+/// A resource limit exceeded by a build
+#[derive(Clone, Debug, Serialize)]
+pub struct LimitError {
+    /// `layers`, `frames` or `flash`
+    pub limit: String,
+    pub used: usize,
+    pub max: usize,
+    pub message: String,
+}
+
+/// Limits of a keyboard on a firmware version, see `BOARD_LIMITS`
+pub fn board_limits(name: &str, firmware: &FirmwareProfile) -> &'static BoardLimits {
+    let name = name.replace(" ", "_").to_lowercase();
+    BOARD_LIMITS
+        .iter()
+        .find(|l| {
+            l.boards.contains(&name.as_str())
+                && (l.versions.is_empty() || l.versions.contains(&firmware.version))
+        })
+        .unwrap_or(&DEFAULT_LIMITS)
+}
+
+fn statement_bytes(statement: &Statement) -> usize {
+    match statement {
+        Statement::Remap { .. } | Statement::Trigger { .. } => BINDING_BYTES,
+        Statement::AnimationSettings { .. } => ANIMATION_BYTES,
+        Statement::AnimationFrame { frame, .. } => {
+            FRAME_BYTES + FRAME_PIXEL_BYTES * frame.matches("P[").count()
+        }
+        Statement::PixelMap { .. } | Statement::PixelPosition { .. } => PIXEL_BYTES,
+        // Custom KLL, assumed to be bindings
+        Statement::Raw(text) => BINDING_BYTES * text.matches(';').count(),
+        Statement::Other(_) => BINDING_BYTES,
+        _ => 0,
+    }
+}
+
+/// Rough flash use of the generated keymap, in bytes
+pub fn estimate_flash(documents: &[KllDocument]) -> usize {
+    documents
+        .iter()
+        .map(|doc| {
+            LAYER_BYTES
+                + doc
+                    .sections
+                    .iter()
+                    .flatten()
+                    .map(statement_bytes)
+                    .sum::<usize>()
+        })
+        .sum()
+}
+
+/// Checks the generated documents against the limits of the keyboard `name` and firmware
+pub fn check_limits(
+    name: &str,
+    firmware: &FirmwareProfile,
+    documents: &[KllDocument],
+) -> Vec<LimitError> {
+    let board = board_limits(name, firmware);
+    let mut errors = Vec::new();
+
+    let max_layers = board.max_layers.min(firmware.max_layers);
+    if documents.len() > max_layers {
+        errors.push(LimitError {
+            limit: "layers".to_string(),
+            used: documents.len(),
+            max: max_layers,
+            message: format!(
+                "{} layers used, {} on {} supports at most {}",
+                documents.len(),
+                name,
+                firmware.version,
+                max_layers
+            ),
+        });
+    }
+
+    let mut frames: Vec<(&str, usize)> = Vec::new();
+    for statement in documents.iter().flat_map(|d| d.sections.iter().flatten()) {
+        if let Statement::AnimationFrame { name, .. } = statement {
+            match frames.iter_mut().find(|(n, _)| n == name) {
+                Some((_, count)) => *count += 1,
+                None => frames.push((name, 1)),
+            }
+        }
+    }
+    let total = frames.iter().map(|(_, count)| count).sum::<usize>();
+    if total > board.max_frames {
+        let mut message = format!("{} animation frames used (", total);
+        for (i, (animation, count)) in frames.iter().enumerate() {
+            let sep = if i > 0 { ", " } else { "" };
+            let _ = write!(message, "{}{}: {}", sep, animation, count);
+        }
+        let _ = write!(
+            message,
+            "), {} on {} supports at most {}",
+            name, firmware.version, board.max_frames
+        );
+        errors.push(LimitError {
+            limit: "frames".to_string(),
+            used: total,
+            max: board.max_frames,
+            message,
+        });
+    }
+
+    let flash = estimate_flash(documents);
+    if flash > board.flash {
+        errors.push(LimitError {
+            limit: "flash".to_string(),
+            used: flash,
+            max: board.flash,
+            message: format!(
+                "Estimated keymap size of {} bytes exceeds the {} bytes of flash available on {} on {}",
+                flash, board.flash, name, firmware.version
+            ),
+        });
+    }
+
+    errors
+}
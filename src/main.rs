@@ -1,9 +1,30 @@
+mod animation;
+mod ast;
 mod build;
+mod firmware;
+mod hid;
+mod import;
 mod kll;
+mod layouts;
+mod limits;
+mod merge;
+mod preview;
+mod render;
+mod scancodes;
 mod versions;
 
 use crate::build::*;
+use crate::firmware::*;
+use crate::hid::*;
+use crate::import::*;
 use crate::kll::*;
+use crate::layouts::*;
+use crate::limits::*;
+use crate::merge::*;
+use crate::preview::*;
+use crate::render::*;
+use crate::scancodes::*;
+use crate::versions::*;
 
 use indexmap::IndexMap;
 use std::collections::hash_map::{DefaultHasher, HashMap};
@@ -12,12 +33,13 @@ use std::hash::{Hash, Hasher};
 use std::path::Path;
 use std::process::Command;
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
     extract::{Query, State},
     http::{header, StatusCode},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{get, post, put},
     Json, Router,
 };
 use tokio::sync::Mutex;
@@ -31,7 +53,6 @@ use shared_child::SharedChild;
 
 const BUILD_ROUTE: &str = "./tmp";
 
-const LAYOUT_DIR: &str = "./layouts";
 const BUILD_DIR: &str = "./tmp_builds";
 const CONFIG_DIR: &str = "./tmp_config";
 
@@ -39,7 +60,8 @@ const STATS_DB_FILE: &str = "./stats.db";
 const STATS_DB_SCHEMA: &str = include_str!("../schema/stats.sqlite");
 
 const CONFIG_DB_FILE: &str = "./config.db";
-const CONFIG_DB_SCHEMA: &str = include_str!("../schema/config.sqlite");
+/// Seconds between controller tag refreshes, overridden by `KIISRV_TAG_REFRESH`
+const TAG_REFRESH_SECS: u64 = 3600;
 
 const CONTROLLER_GIT_URL: &str = "https://github.com/kiibohd/controller.git";
 const CONTROLLER_GIT_REMOTE: &str = "controller";
@@ -48,12 +70,22 @@ const CONTROLLER_GIT_REMOTE: &str = "controller";
 pub struct BuildRequest {
     pub config: KllConfig,
     pub env: String,
+    /// Only emit bindings that differ from the base layout
+    #[serde(default)]
+    pub changed_only: bool,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ImportRequest {
+    pub files: Vec<String>,
+    pub base: Option<String>,
 }
 
 #[derive(Clone, Serialize)]
 pub struct BuildResult {
     pub filename: String,
     pub success: bool,
+    pub warnings: Vec<BuildWarning>,
 }
 
 #[derive(Clone)]
@@ -66,9 +98,12 @@ pub enum JobEntry {
 pub struct AppState {
     job_queue: Arc<Mutex<HashMap<String, JobEntry>>>,
     stats_db: Arc<Mutex<Connection>>,
-    versions: Arc<HashMap<String, VersionInfo>>,
+    config_db: Arc<Mutex<Connection>>,
+    versions: Arc<Mutex<HashMap<String, VersionInfo>>>,
+    layout_cache: Arc<Mutex<HashMap<String, CachedLayout>>>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 struct RequestLog {
     id: i32,
@@ -87,44 +122,238 @@ struct RequestLog {
     build_duration: Option<i32>,
 }
 
-#[derive(Debug)]
-struct VersionMap {
-    name: String,
-    channel: String,
-    container: String,
-    git_tag: String,
+#[derive(Deserialize)]
+struct LayoutParams {
+    rev: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct LayoutParams {
+struct RenderParams {
+    rev: Option<String>,
+    layer: Option<usize>,
+    leds: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct PreviewParams {
     rev: Option<String>,
+    /// `gif` for an animated image instead of the JSON frames
+    format: Option<String>,
+    /// Milliseconds between frames of the image
+    delay: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct MergeRequest {
+    config: KllConfig,
+    from: String,
+    to: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MigrateRequest {
+    config: KllConfig,
+    from: String,
+    to: String,
+}
+
+#[derive(Serialize)]
+struct MergeResponse {
+    config: KllConfig,
+    conflicts: Vec<Conflict>,
+}
+
+/// A layout and the commit `rev` resolved to
+async fn load_layout(
+    state: &AppState,
+    file: &str,
+    rev: &str,
+) -> Result<(CachedLayout, String), StatusCode> {
+    let (key, commit) = layout_key(file, rev)?;
+    if let Some(layout) = state.layout_cache.lock().await.get(&key) {
+        return Ok((layout.clone(), commit));
+    }
+
+    let layout = read_layout(&key)?;
+    state.layout_cache.lock().await.insert(key, layout.clone());
+    Ok((layout, commit))
+}
+
+async fn layout_at_rev(state: &AppState, file: &str, rev: &str) -> Result<KllConfig, StatusCode> {
+    let (layout, _) = load_layout(state, file, rev).await?;
+    serde_json::from_str(&layout.content).map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)
 }
 
 async fn get_layout(
+    State(state): State<AppState>,
     axum::extract::Path(file): axum::extract::Path<String>,
     Query(params): Query<LayoutParams>,
+    headers: axum::http::HeaderMap,
 ) -> Result<Response, StatusCode> {
     let rev = params.rev.unwrap_or_else(|| "HEAD".to_string());
 
-    let path = std::path::PathBuf::from(format!("{}/{}", LAYOUT_DIR, file));
-    let realfile = fs::read_link(&path).unwrap_or(std::path::PathBuf::from(&file));
-    let realpath = format!("{}/{}", LAYOUT_DIR, realfile.to_str().unwrap());
-    
     tracing::info!("Get layout {:?} ({})", file, rev);
 
-    let result = Command::new("git")
-        .args(&["show", &format!("{}:{}", rev, realpath)])
-        .output()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    
-    let content = String::from_utf8_lossy(&result.stdout).to_string();
+    let (layout, commit) = load_layout(&state, &file, &rev).await?;
+    Ok(layout_response(&layout, is_pinned(&commit, &rev), &headers))
+}
 
-    Ok((
+fn svg_response(svg: String) -> Response {
+    (
         StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/json")],
-        content,
+        [(header::CONTENT_TYPE, "image/svg+xml")],
+        svg,
     )
-        .into_response())
+        .into_response()
+}
+
+async fn render_layout(
+    State(state): State<AppState>,
+    axum::extract::Path(file): axum::extract::Path<String>,
+    Query(params): Query<RenderParams>,
+) -> Result<Response, StatusCode> {
+    let rev = params.rev.unwrap_or_else(|| "HEAD".to_string());
+    let layer = params.layer.unwrap_or(0);
+    tracing::info!("Render layout {:?} ({}) layer {}", file, rev, layer);
+
+    let config = layout_at_rev(&state, &file, &rev).await?;
+    Ok(svg_response(render_svg(
+        &config,
+        layer,
+        params.leds.unwrap_or(false),
+    )))
+}
+
+async fn render_config(
+    Query(params): Query<RenderParams>,
+    Json(config): Json<KllConfig>,
+) -> Result<Response, StatusCode> {
+    Ok(svg_response(render_svg(
+        &config,
+        params.layer.unwrap_or(0),
+        params.leds.unwrap_or(false),
+    )))
+}
+
+fn preview_response(
+    config: &KllConfig,
+    animation: &str,
+    params: &PreviewParams,
+) -> Result<Response, StatusCode> {
+    let leds = config.leds.clone().unwrap_or_default();
+    let preview = match animation_frames(config, animation)
+        .and_then(|(settings, frames)| simulate(&settings, &frames, &leds))
+    {
+        Ok(preview) => preview,
+        Err(e) => return Ok((StatusCode::BAD_REQUEST, Json(vec![e])).into_response()),
+    };
+
+    match params.format.as_deref() {
+        Some("gif") => match render_gif(config, &preview, params.delay.unwrap_or(50)) {
+            Ok(gif) => {
+                Ok((StatusCode::OK, [(header::CONTENT_TYPE, "image/gif")], gif).into_response())
+            }
+            Err(e) => Ok((StatusCode::BAD_REQUEST, Json(vec![e])).into_response()),
+        },
+        Some(_) => Err(StatusCode::BAD_REQUEST),
+        None => Ok((StatusCode::OK, Json(preview)).into_response()),
+    }
+}
+
+async fn preview_layout(
+    State(state): State<AppState>,
+    axum::extract::Path((file, animation)): axum::extract::Path<(String, String)>,
+    Query(params): Query<PreviewParams>,
+) -> Result<Response, StatusCode> {
+    let rev = params.rev.clone().unwrap_or_else(|| "HEAD".to_string());
+    tracing::info!("Preview animation {} of {:?} ({})", animation, file, rev);
+
+    let config = layout_at_rev(&state, &file, &rev).await?;
+    preview_response(&config, &animation, &params)
+}
+
+async fn preview_config(
+    axum::extract::Path(animation): axum::extract::Path<String>,
+    Query(params): Query<PreviewParams>,
+    Json(config): Json<KllConfig>,
+) -> Result<Response, StatusCode> {
+    preview_response(&config, &animation, &params)
+}
+
+async fn merge_layout(
+    State(state): State<AppState>,
+    axum::extract::Path(file): axum::extract::Path<String>,
+    Json(body): Json<MergeRequest>,
+) -> Result<Response, StatusCode> {
+    let to = body.to.unwrap_or_else(|| "HEAD".to_string());
+    tracing::info!("Merge layout {:?} ({} -> {})", file, body.from, to);
+
+    let old = layout_at_rev(&state, &file, &body.from).await?;
+    let new = layout_at_rev(&state, &file, &to).await?;
+
+    let merged = merge_matrix(&old.matrix, &new.matrix, &body.config.matrix);
+    let mut config = body.config;
+    config.matrix = merged.matrix;
+
+    let result = MergeResponse {
+        config,
+        conflicts: merged.conflicts,
+    };
+    Ok((StatusCode::OK, Json(result)).into_response())
+}
+
+async fn migrate_request(Json(body): Json<MigrateRequest>) -> Result<Response, StatusCode> {
+    let mut config = body.config;
+    tracing::info!(
+        "Migrate {} config ({} -> {})",
+        config.header.name,
+        body.from,
+        body.to
+    );
+
+    let header = &config.header;
+    match migrate_matrix(
+        &header.name,
+        &header.base,
+        &config.matrix,
+        &body.from,
+        &body.to,
+    ) {
+        Ok(matrix) => config.matrix = matrix,
+        Err(e) => return Ok((StatusCode::BAD_REQUEST, Json(vec![e])).into_response()),
+    }
+    Ok((StatusCode::OK, Json(config)).into_response())
+}
+
+async fn validate_request(Json(config): Json<KllConfig>) -> Result<Response, StatusCode> {
+    let key_errors = validate_keys(&config);
+    Ok((StatusCode::OK, Json(key_errors)).into_response())
+}
+
+async fn import_request(Json(body): Json<ImportRequest>) -> Result<Response, StatusCode> {
+    let base_file = match body.base {
+        Some(base) => base,
+        None => body
+            .files
+            .first()
+            .and_then(|f| base_layout_file(f))
+            .ok_or(StatusCode::BAD_REQUEST)?,
+    };
+    tracing::info!("Import {} file(s) against {}", body.files.len(), base_file);
+
+    let path = Path::new(LAYOUT_DIR).join(&base_file);
+    if !is_layout_name(&base_file) || !path.is_file() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let base: KllConfig = {
+        let contents = fs::read_to_string(&path).map_err(|_| StatusCode::NOT_FOUND)?;
+        serde_json::from_str(&contents).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    };
+
+    match import_kll(&base, &body.files) {
+        Ok(config) => Ok((StatusCode::OK, Json(config)).into_response()),
+        Err(e) => Ok((StatusCode::BAD_REQUEST, Json(e)).into_response()),
+    }
 }
 
 async fn build_request(
@@ -162,12 +391,64 @@ async fn build_request(
     let request_time: DateTime<Utc> = Utc::now();
 
     let config = body.config;
-    let container = match body.env.as_ref() {
-        "lts" => "controller-050",
-        "nightly" => "controller-057",
-        "latest" | _ => "controller-057",
+    let canned_errors = validate_canned(&config);
+    if !canned_errors.is_empty() {
+        tracing::info!(
+            "Rejected request with {} invalid animation(s)",
+            canned_errors.len()
+        );
+        return Ok((StatusCode::BAD_REQUEST, Json(canned_errors)).into_response());
+    }
+    let resolved = resolve_version(&*state.versions.lock().await, &body.env)
+        .map(|(name, info)| (name.clone(), info.git_tag.clone(), info.container.clone()));
+    let (version, container) = match resolved {
+        Some((name, version, container)) => {
+            tracing::info!("Version: {} -> {} in {}", body.env, name, container);
+            (version, container)
+        }
+        None => {
+            let image = state
+                .versions
+                .lock()
+                .await
+                .get(&body.env)
+                .map(|v| v.image.image.clone());
+            let known = version_rows(&*state.config_db.lock().await)
+                .iter()
+                .any(|v| v.name == body.env || v.git_tag == body.env || v.channel == body.env);
+            let error = if let Some(image) = image {
+                format!(
+                    "Firmware version {} is not available, image {} hasn't been built",
+                    body.env, image
+                )
+            } else if known {
+                format!("Firmware version {} is not available", body.env)
+            } else {
+                format!("Unknown firmware version {}", body.env)
+            };
+            tracing::info!("Rejected request: {}", error);
+            return Ok((StatusCode::BAD_REQUEST, Json(vec![error])).into_response());
+        }
+    };
+    let firmware = firmware_profile(&version);
+    let unsupported = unsupported_actions(&config, firmware);
+    if !unsupported.is_empty() {
+        tracing::info!(
+            "Rejected request with {} unsupported action(s)",
+            unsupported.len()
+        );
+        return Ok((StatusCode::BAD_REQUEST, Json(unsupported)).into_response());
+    }
+    let key_errors = validate_keys(&config);
+    if !key_errors.is_empty() {
+        tracing::info!("Rejected request with {} invalid key(s)", key_errors.len());
+        return Ok((StatusCode::BAD_REQUEST, Json(key_errors)).into_response());
+    }
+    let unmatched = unmatched_keys(&config, firmware);
+    if !unmatched.is_empty() {
+        tracing::info!("Rejected request with {} unmatched key(s)", unmatched.len());
+        return Ok((StatusCode::BAD_REQUEST, Json(unmatched)).into_response());
     }
-    .to_string();
 
     let config_str = serde_json::to_string(&config).unwrap();
 
@@ -175,11 +456,34 @@ async fn build_request(
         let mut hasher = DefaultHasher::new();
         container.hash(&mut hasher);
         config_str.hash(&mut hasher);
+        body.changed_only.hash(&mut hasher);
         let h = hasher.finish();
         format!("{:x}", h)
     };
     tracing::info!("Received request: {}", hash);
 
+    // Generated up front so cached builds report the same warnings
+    let (documents, mut warnings) = generate_kll_documents(&config, firmware, body.changed_only);
+    let custom_issues = validate_custom(&config, &documents);
+    if custom_issues.iter().any(|i| i.error) {
+        tracing::info!("Rejected request with invalid custom KLL");
+        return Ok((StatusCode::BAD_REQUEST, Json(custom_issues)).into_response());
+    }
+    let limit_errors = check_limits(&config.header.name, firmware, &documents);
+    if !limit_errors.is_empty() {
+        tracing::info!("Rejected request exceeding {} limit(s)", limit_errors.len());
+        return Ok((StatusCode::BAD_REQUEST, Json(limit_errors)).into_response());
+    }
+    let files = kll_files(documents);
+    warnings.extend(custom_issues.into_iter().map(|issue| BuildWarning {
+        layer: Some(issue.layer),
+        code: None,
+        message: format!("Custom KLL line {}: {}", issue.line, issue.message),
+    }));
+    for warning in warnings.iter() {
+        tracing::info!("Warning: {:?}", warning);
+    }
+
     let info = configure_build(&config, vec!["".to_string()]);
     let mut output_file = format!("{}-{}-{}.zip", info.name, info.layout, hash);
 
@@ -187,9 +491,9 @@ async fn build_request(
         let mut queue = state.job_queue.lock().await;
         let job = queue.get(&hash);
 
-        if job.is_some() {
+        if let Some(job) = job {
             tracing::info!(" > Existing task");
-            job.unwrap().clone()
+            job.clone()
         } else {
             tracing::info!(" > Starting new build in container {}", container);
 
@@ -197,11 +501,15 @@ async fn build_request(
             fs::create_dir_all(&config_dir).expect("Could not create directory");
 
             let mut layers: Vec<String> = Vec::new();
-            let files = generate_kll(&config, body.env == "lts");
-            for file in files {
+            for (n, file) in files.into_iter().enumerate() {
                 let filename = format!("{}/{}", config_dir, file.name);
                 fs::write(&filename, file.content).expect("Could not write kll file");
-                layers.push(format!("{}", filename));
+                layers.push(filename);
+
+                // Picture of the layer, build.sh adds *.svg from the config dir to the zip
+                let svg_file = format!("{}/{}", config_dir, file.name.replace(".kll", ".svg"));
+                fs::write(&svg_file, render_svg(&config, n, n == 0))
+                    .expect("Could not write svg file");
             }
 
             tracing::info!("{:?}", layers);
@@ -249,7 +557,7 @@ async fn build_request(
         build_duration
     );
 
-    let layers = vec![""];
+    let layers = [""];
     {
         let db = state.stats_db.lock().await;
         db.execute(
@@ -282,6 +590,7 @@ async fn build_request(
     let result = BuildResult {
         filename: format!("{}/{}", BUILD_ROUTE, output_file),
         success,
+        warnings,
     };
 
     Ok((StatusCode::OK, Json(result)).into_response())
@@ -337,7 +646,7 @@ async fn stats(State(state): State<AppState>) -> Result<Response, StatusCode> {
         *container_counts.entry(request.container).or_insert(0) += 1;
 
         total_layers += request.layers as usize;
-        total_buildtime += request.build_duration.unwrap_or(0) as i32;
+        total_buildtime += request.build_duration.unwrap_or(0);
 
         hashes.push(request.hash);
         users.push(request.ip_addr);
@@ -393,75 +702,194 @@ async fn stats(State(state): State<AppState>) -> Result<Response, StatusCode> {
 async fn versions_request(State(state): State<AppState>) -> Result<Response, StatusCode> {
     let versions: HashMap<String, Option<ReleaseInfo>> = state
         .versions
+        .lock()
+        .await
         .iter()
+        .filter(|(_, v)| v.image.available)
         .map(|(k, v)| (k.clone(), v.info.clone()))
         .collect();
 
     Ok((StatusCode::OK, Json(versions)).into_response())
 }
 
-fn version_map(db: Connection) -> HashMap<String, VersionInfo> {
-    let mut stmt = db.prepare("SELECT * FROM Versions").unwrap();
-    let rows = stmt
-        .query_map([], |row| {
-            Ok(VersionMap {
-                name: row.get(0)?,
-                channel: row.get(1)?,
-                container: row.get(2)?,
-                git_tag: row.get(3)?,
-            })
-        })
-        .unwrap();
-    let versions: Vec<VersionMap> = rows.map(|r| r.unwrap()).collect();
-
-    let containers = list_containers();
-    let tags = fetch_tags();
-    versions
-        .into_iter()
-        .filter(|v| containers.contains(&v.container))
-        .map(|v| {
-            (
-                v.name,
-                VersionInfo {
-                    container: v.container,
-                    channel: v.channel,
-                    info: tags.get(&v.git_tag).cloned(),
-                },
-            )
-        })
-        .collect()
+/// Admin requests need `Authorization: Bearer $KIISRV_ADMIN_TOKEN`, they're disabled when
+/// the token isn't set.
+fn check_admin(headers: &axum::http::HeaderMap) -> Result<(), StatusCode> {
+    let token = std::env::var("KIISRV_ADMIN_TOKEN").map_err(|_| StatusCode::FORBIDDEN)?;
+    let auth = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok());
+    match admin_authorized(&token, auth) {
+        true => Ok(()),
+        false => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+async fn admin_versions(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, StatusCode> {
+    check_admin(&headers)?;
+    let versions = version_rows(&*state.config_db.lock().await);
+    Ok((StatusCode::OK, Json(versions)).into_response())
+}
+
+/// Adds a version, or updates the fields given for an existing one
+async fn admin_update_version(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    Json(body): Json<VersionUpdate>,
+) -> Result<Response, StatusCode> {
+    check_admin(&headers)?;
+    let version = update_version(&*state.config_db.lock().await, &name, body)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(version) = version else {
+        let error = "New versions need a channel, container and git_tag";
+        return Ok((StatusCode::BAD_REQUEST, Json(vec![error])).into_response());
+    };
+    tracing::info!("Update version {:?}", version);
+    reload_versions(&state).await;
+
+    Ok((StatusCode::OK, Json(version)).into_response())
+}
+
+/// Retires a version, it's no longer listed or built
+async fn admin_retire_version(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<Response, StatusCode> {
+    check_admin(&headers)?;
+    let removed = retire_version(&*state.config_db.lock().await, &name)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !removed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    tracing::info!("Retired version {}", name);
+    reload_versions(&state).await;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
 }
 
-fn fetch_tags() -> IndexMap<String, ReleaseInfo> {
+/// Every version with a container, including the state of its image
+async fn versions_status(State(state): State<AppState>) -> Result<Response, StatusCode> {
+    let versions = state.versions.lock().await.clone();
+    Ok((StatusCode::OK, Json(versions)).into_response())
+}
+
+#[derive(Serialize)]
+struct Changelog {
+    from: String,
+    to: String,
+    commits: Vec<ChangelogEntry>,
+}
+
+/// Commits between two versions (names or tags), `GET /versions/lts..latest/changelog`
+async fn changelog_request(
+    State(state): State<AppState>,
+    axum::extract::Path(range): axum::extract::Path<String>,
+) -> Result<Response, StatusCode> {
+    let (from, to) = split_range(&range).ok_or(StatusCode::BAD_REQUEST)?;
+    tracing::info!("Changelog {} -> {}", from, to);
+
+    // Only cached tags are passed to git, not arbitrary revisions
+    let tags = cached_tags(&*state.config_db.lock().await);
+    let versions = state.versions.lock().await;
+    let tag = |version: &str| {
+        let tag = versions
+            .get(version)
+            .map_or(version, |v| v.git_tag.as_str());
+        tags.get_key_value(tag)
+            .map(|(tag, info)| (tag.clone(), info.hash.clone()))
+    };
+    let (from_tag, from_hash) = tag(from).ok_or(StatusCode::NOT_FOUND)?;
+    let (to_tag, to_hash) = tag(to).ok_or(StatusCode::NOT_FOUND)?;
+    drop(versions);
+
+    let result = Command::new("git")
+        .args([
+            "log",
+            "--pretty=tformat:%H%x09%ai%x09%s",
+            &format!("{}..{}", from_hash, to_hash),
+        ])
+        .output()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !result.status.success() {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let changelog = Changelog {
+        from: from_tag,
+        to: to_tag,
+        commits: parse_changelog(&String::from_utf8_lossy(&result.stdout)),
+    };
+    Ok((StatusCode::OK, Json(changelog)).into_response())
+}
+
+fn version_map(db: &Connection) -> HashMap<String, VersionInfo> {
+    version_infos(version_rows(db), &cached_tags(db), &container_images())
+}
+
+/// Rebuilds the served versions from the database. Docker can be slow, so it's asked before
+/// taking any lock.
+async fn reload_versions(state: &AppState) {
+    let images = tokio::task::spawn_blocking(container_images)
+        .await
+        .unwrap_or_default();
+    let db = state.config_db.lock().await;
+    *state.versions.lock().await = version_infos(version_rows(&db), &cached_tags(&db), &images);
+}
+
+/// Fetches the controller remote and reads the metadata of its tags. Tags that still point
+/// to the same commit as in `cached` are reused. Returns `None` when the remote can't be
+/// reached.
+fn fetch_tags(cached: IndexMap<String, ReleaseInfo>) -> Option<IndexMap<String, ReleaseInfo>> {
+    let fetched = Command::new("git")
+        .args(["fetch", CONTROLLER_GIT_REMOTE])
+        .output()
+        .ok()?;
+    if !fetched.status.success() {
+        tracing::warn!("Could not fetch {}", CONTROLLER_GIT_REMOTE);
+        return None;
+    }
+
     let result = Command::new("git")
-        .args(&["ls-remote", "--tags", CONTROLLER_GIT_REMOTE])
+        .args(["ls-remote", "--tags", CONTROLLER_GIT_REMOTE])
         .output()
-        .expect("Failed!");
+        .ok()?;
+    if !result.status.success() {
+        return None;
+    }
     let out = String::from_utf8_lossy(&result.stdout);
-    let map = out
-        .lines()
-        .filter(|l| !l.contains("^{}"))
-        .filter_map(|l| {
-            let mut parts = l.split('\t');
-            Some((parts.next()?.trim(), parts.next()?.trim()))
-        });
+    let map = out.lines().filter(|l| !l.contains("^{}")).filter_map(|l| {
+        let mut parts = l.split('\t');
+        Some((parts.next()?.trim(), parts.next()?.trim()))
+    });
 
     let mut versions = IndexMap::new();
 
     for (h, t) in map.rev() {
         let hash = h.to_string();
         let tag = t.replace("refs/tags/", "");
+        if let Some(info) = cached.get(&tag).filter(|i| i.hash == hash) {
+            versions.insert(tag, info.clone());
+            continue;
+        }
 
         let result = Command::new("git")
-            .args(&["rev-list", "--count", h])
+            .args(["rev-list", "--count", h])
             .output()
-            .expect("Failed!");
-        let commit: u16 = String::from_utf8_lossy(&result.stdout)
+            .ok()?;
+        let Ok(commit) = String::from_utf8_lossy(&result.stdout)
             .trim()
-            .parse()
-            .unwrap();
+            .parse::<u16>()
+        else {
+            tracing::warn!("Skipping tag {}, commit {} is missing", tag, h);
+            continue;
+        };
         let msb = ((commit & 0xFF00) >> 8) as u8;
-        let lsb = ((commit & 0x00FF) >> 0) as u8;
+        let lsb = (commit & 0x00FF) as u8;
 
         fn bcd_format(x: u8) -> String {
             if x > 99 {
@@ -473,9 +901,9 @@ fn fetch_tags() -> IndexMap<String, ReleaseInfo> {
         let bcd = format!("{}.{}", bcd_format(msb), bcd_format(lsb));
 
         let result = Command::new("git")
-            .args(&["log", "-1", "--pretty=tformat:%ai", h])
+            .args(["log", "-1", "--pretty=tformat:%ai", h])
             .output()
-            .expect("Failed!");
+            .ok()?;
         let out = String::from_utf8_lossy(&result.stdout);
         let date = out.trim().to_string();
 
@@ -492,23 +920,35 @@ fn fetch_tags() -> IndexMap<String, ReleaseInfo> {
         );
     }
 
-    versions
+    Some(versions)
 }
 
-#[derive(Debug, Clone)]
-pub struct VersionInfo {
-    container: String,
-    channel: String,
-    info: Option<ReleaseInfo>,
-}
-
-#[derive(Debug, Clone, Serialize)]
-pub struct ReleaseInfo {
-    commit: u16,
-    date: String,
-    hash: String,
-    bcd: String,
-    notes: String,
+/// Refreshes the cached tag metadata and image availability in the background, versions keep
+/// being served from the cache while the remote is unreachable.
+async fn refresh_tags(state: AppState) {
+    let secs = std::env::var("KIISRV_TAG_REFRESH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(TAG_REFRESH_SECS);
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+    loop {
+        interval.tick().await;
+        let cached = cached_tags(&*state.config_db.lock().await);
+        let tags = tokio::task::spawn_blocking(move || fetch_tags(cached))
+            .await
+            .ok()
+            .flatten();
+
+        match tags {
+            Some(tags) => {
+                tracing::info!("Refreshed {} controller tags", tags.len());
+                store_tags(&*state.config_db.lock().await, &tags);
+            }
+            None => tracing::warn!("Tag refresh failed, serving cached tags"),
+        }
+        // Also picks up images built or removed since the last refresh
+        reload_versions(&state).await;
+    }
 }
 
 #[tokio::main]
@@ -517,50 +957,69 @@ async fn main() {
 
     // Check if remote exists, add it if it doesn't
     let remote_exists = Command::new("git")
-        .args(&["remote", "get-url", CONTROLLER_GIT_REMOTE])
+        .args(["remote", "get-url", CONTROLLER_GIT_REMOTE])
         .output()
         .map(|o| o.status.success())
         .unwrap_or(false);
 
     if !remote_exists {
         let _ = Command::new("git")
-            .args(&["remote", "add", CONTROLLER_GIT_REMOTE, CONTROLLER_GIT_URL])
+            .args(["remote", "add", CONTROLLER_GIT_REMOTE, CONTROLLER_GIT_URL])
             .output();
     }
 
-    let _ = Command::new("git")
-        .args(&["fetch", CONTROLLER_GIT_REMOTE])
-        .output()
-        .expect("Failed to fetch git remote");
-
     let queue: HashMap<String, JobEntry> = HashMap::new();
 
     let config_db = Connection::open(Path::new(CONFIG_DB_FILE)).unwrap();
-    config_db.execute(CONFIG_DB_SCHEMA, []).unwrap();
+    init_config_db(&config_db).unwrap();
 
     let stats_db = Connection::open(Path::new(STATS_DB_FILE)).unwrap();
     stats_db.execute(STATS_DB_SCHEMA, []).unwrap();
 
-    let containers = list_containers();
-    tracing::info!("\nPossible containers:");
-    tracing::info!("{:#?}", containers);
-
-    let versions = version_map(config_db);
+    let versions = version_map(&config_db);
     tracing::info!("\nVersions:");
     for (v, i) in versions.iter() {
-        tracing::info!("{} -> {} [{}]", v, i.container, i.channel);
+        tracing::info!(
+            "{} -> {} [{}] available: {}",
+            v,
+            i.container,
+            i.channel,
+            i.image.available
+        );
     }
 
     let state = AppState {
         job_queue: Arc::new(Mutex::new(queue)),
         stats_db: Arc::new(Mutex::new(stats_db)),
-        versions: Arc::new(versions),
+        config_db: Arc::new(Mutex::new(config_db)),
+        versions: Arc::new(Mutex::new(versions)),
+        layout_cache: Arc::new(Mutex::new(HashMap::new())),
     };
 
+    tokio::spawn(refresh_tags(state.clone()));
+
     let app = Router::new()
         .route("/versions", get(versions_request))
+        .route("/versions/status", get(versions_status))
+        .route("/versions/:range/changelog", get(changelog_request))
         .route("/stats", get(stats))
+        .route("/admin/versions", get(admin_versions))
+        .route(
+            "/admin/versions/:name",
+            put(admin_update_version).delete(admin_retire_version),
+        )
         .route("/layouts/:file", get(get_layout))
+        .route("/layouts/:file/merge", post(merge_layout))
+        .route("/layouts/:file/render.svg", get(render_layout))
+        .route("/render.svg", post(render_config))
+        .route(
+            "/layouts/:file/animations/:animation/preview",
+            get(preview_layout),
+        )
+        .route("/animations/:animation/preview", post(preview_config))
+        .route("/import", post(import_request))
+        .route("/validate", post(validate_request))
+        .route("/migrate", post(migrate_request))
         .nest_service("/tmp", ServeDir::new(BUILD_DIR))
         .fallback(post(build_request)) // Catch-all POST handler (like Iron's mount at "/")
         .layer(TraceLayer::new_for_http())
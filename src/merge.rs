@@ -0,0 +1,183 @@
+use crate::kll::*;
+
+use indexmap::IndexMap;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// A binding that was changed both in the user's config and in the updated base layout.
+/// The user's binding is kept in the merged config.
+#[derive(Clone, Debug, Serialize)]
+pub struct Conflict {
+    pub code: String,
+    pub layer: Option<usize>,
+    pub old: Option<KeyAction>,
+    pub new: Option<KeyAction>,
+    pub user: Option<KeyAction>,
+}
+
+pub struct MergeResult {
+    pub matrix: Vec<MatrixKey>,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Scan code and occurrence of a key, split boards repeat scan codes on each half
+type KeyId = (String, usize);
+
+fn key_ids(matrix: &[MatrixKey]) -> Vec<KeyId> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    matrix
+        .iter()
+        .map(|key| {
+            let code = key.code.to_lowercase();
+            let n = seen.entry(code.clone()).or_insert(0);
+            *n += 1;
+            (code, *n - 1)
+        })
+        .collect()
+}
+
+fn find<'a>(matrix: &'a [MatrixKey], ids: &[KeyId], id: &KeyId) -> Option<&'a MatrixKey> {
+    ids.iter().position(|i| i == id).map(|i| &matrix[i])
+}
+
+fn geometry(key: &MatrixKey) -> [Option<f32>; 4] {
+    [key.x, key.y, key.w, key.h]
+}
+
+fn action(key: Option<&MatrixKey>, layer: usize) -> Option<&KeyAction> {
+    key.and_then(|k| k.layers.get(&layer))
+}
+
+fn same_bindings(a: &MatrixKey, b: &MatrixKey) -> bool {
+    a.layers.len() == b.layers.len() && a.layers.iter().all(|(l, x)| action(Some(b), *l) == Some(x))
+}
+
+/// Picks a side for a single value: `Some(true)` takes the new base, `Some(false)` keeps the
+/// user's. Returns `None` when both sides changed it differently.
+fn merge_value<T: PartialEq>(old: &T, new: &T, user: &T) -> Option<bool> {
+    if user == new || new == old {
+        Some(false)
+    } else if user == old {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+fn merge_key(
+    old: Option<&MatrixKey>,
+    new: &MatrixKey,
+    user: &MatrixKey,
+    conflicts: &mut Vec<Conflict>,
+) -> MatrixKey {
+    let mut merged = user.clone();
+
+    let old_geometry = old.map(geometry);
+    if merge_value(&old_geometry, &Some(geometry(new)), &Some(geometry(user))) == Some(true) {
+        merged.x = new.x;
+        merged.y = new.y;
+        merged.w = new.w;
+        merged.h = new.h;
+    }
+
+    let mut layers: Vec<usize> = new
+        .layers
+        .keys()
+        .chain(user.layers.keys())
+        .copied()
+        .collect();
+    if let Some(old) = old {
+        layers.extend(old.layers.keys());
+    }
+    layers.sort();
+    layers.dedup();
+
+    let mut merged_layers = IndexMap::new();
+    for l in layers {
+        let o = action(old, l);
+        let n = action(Some(new), l);
+        let u = action(Some(user), l);
+
+        let merged_action = match merge_value(&o, &n, &u) {
+            Some(true) => n,
+            Some(false) => u,
+            None => {
+                conflicts.push(Conflict {
+                    code: user.code.clone(),
+                    layer: Some(l),
+                    old: o.cloned(),
+                    new: n.cloned(),
+                    user: u.cloned(),
+                });
+                u
+            }
+        };
+        if let Some(action) = merged_action {
+            merged_layers.insert(l, action.clone());
+        }
+    }
+    merged.layers = merged_layers;
+
+    merged
+}
+
+/// Three-way merge of a user's matrix against an updated base layout.
+///
+/// Keys are matched by scan code, the n-th key with a code matches the n-th one on the other
+/// side. Per layer, whichever side changed a binding (including its combo, sequence, hold and
+/// release) relative to the old base wins; if both changed it to different values the user's
+/// binding is kept and a conflict is reported. Keys are returned in the order of the new base,
+/// followed by any keys only the user has.
+pub fn merge_matrix(old: &[MatrixKey], new: &[MatrixKey], user: &[MatrixKey]) -> MergeResult {
+    let mut matrix = Vec::new();
+    let mut conflicts = Vec::new();
+    let (old_ids, new_ids, user_ids) = (key_ids(old), key_ids(new), key_ids(user));
+
+    for (new_key, id) in new.iter().zip(new_ids.iter()) {
+        let old_key = find(old, &old_ids, id);
+        match (find(user, &user_ids, id), old_key) {
+            (Some(user_key), _) => {
+                matrix.push(merge_key(old_key, new_key, user_key, &mut conflicts));
+            }
+            // Added to the base layout
+            (None, None) => matrix.push(new_key.clone()),
+            // Removed by the user, keep it removed unless the base changed it
+            (None, Some(old_key)) => {
+                if !same_bindings(old_key, new_key) {
+                    conflicts.push(Conflict {
+                        code: new_key.code.clone(),
+                        layer: None,
+                        old: action(Some(old_key), 0).cloned(),
+                        new: action(Some(new_key), 0).cloned(),
+                        user: None,
+                    });
+                }
+            }
+        }
+    }
+
+    for (user_key, id) in user.iter().zip(user_ids.iter()) {
+        if new_ids.contains(id) {
+            continue;
+        }
+        match find(old, &old_ids, id) {
+            // Removed from the base layout, drop it unless the user changed it
+            Some(old_key) => {
+                if !same_bindings(old_key, user_key) {
+                    conflicts.push(Conflict {
+                        code: user_key.code.clone(),
+                        layer: None,
+                        old: action(Some(old_key), 0).cloned(),
+                        new: None,
+                        user: action(Some(user_key), 0).cloned(),
+                    });
+                    matrix.push(user_key.clone());
+                }
+            }
+            // Added by the user
+            None => matrix.push(user_key.clone()),
+        }
+    }
+
+    MergeResult { matrix, conflicts }
+}
@@ -0,0 +1,458 @@
+use crate::animation::generate_frames;
+use crate::import::split_unquoted;
+use crate::kll::*;
+use crate::render::LED_MM_PER_UNIT;
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// LEDs set by a pixel of an animation frame
+#[derive(Clone, Debug, PartialEq)]
+pub enum PixelTarget {
+    /// `P[1]`, `P[1-4]`, `P[1, 3]`
+    Ids(Vec<usize>),
+    /// `P[c:10%]`, position in percent of the LED columns. Without `%` it's in mm from the
+    /// leftmost LED.
+    Column(f32),
+    /// `P[r:10%]`, same as a column but vertical
+    Row(f32),
+}
+
+/// A pixel of an animation frame, `P[c:10%](255,0,0)`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pixel {
+    pub target: PixelTarget,
+    pub color: [u8; 3],
+}
+
+/// Simulated animation, `frames[i][j]` is the color of `leds[j]` in frame `i`
+#[derive(Clone, Debug, Serialize)]
+pub struct Preview {
+    pub leds: Vec<usize>,
+    pub frames: Vec<Vec<[u8; 3]>>,
+}
+
+fn parse_position(s: &str) -> Result<(f32, bool), String> {
+    let (value, percent) = match s.trim().strip_suffix('%') {
+        Some(v) => (v, true),
+        None => (s.trim(), false),
+    };
+    value
+        .trim()
+        .parse::<f32>()
+        .map(|v| (v, percent))
+        .map_err(|_| format!("Invalid pixel position {}", s.trim()))
+}
+
+/// Largest pixel index accepted in a frame, no supported board comes close
+pub const MAX_PIXEL_ID: usize = 1024;
+
+fn parse_target(s: &str) -> Result<(PixelTarget, bool), String> {
+    let s = s.trim();
+    if let Some(column) = s.strip_prefix("c:") {
+        let (pos, percent) = parse_position(column)?;
+        return Ok((PixelTarget::Column(pos), percent));
+    }
+    if let Some(row) = s.strip_prefix("r:") {
+        let (pos, percent) = parse_position(row)?;
+        return Ok((PixelTarget::Row(pos), percent));
+    }
+
+    let mut ids = Vec::new();
+    for part in s.split(',') {
+        let id = |s: &str| match s.trim().parse::<usize>() {
+            Ok(id) if id <= MAX_PIXEL_ID => Ok(id),
+            Ok(_) => Err(format!(
+                "Pixel index {} is larger than {}",
+                s.trim(),
+                MAX_PIXEL_ID
+            )),
+            Err(_) => Err(format!("Invalid pixel index {}", s.trim())),
+        };
+        match part.split_once('-') {
+            Some((from, to)) => ids.extend(id(from)?..=id(to)?),
+            None => ids.push(id(part)?),
+        }
+    }
+    Ok((PixelTarget::Ids(ids), true))
+}
+
+fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let channels = s
+        .split(',')
+        .map(|c| {
+            c.trim()
+                .parse::<u8>()
+                .map_err(|_| format!("Invalid channel value {}", c.trim()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    match channels[..] {
+        [v] => Ok([v, v, v]),
+        [r, g, b] => Ok([r, g, b]),
+        _ => Err(format!("Expected 1 or 3 channel values, found ({})", s)),
+    }
+}
+
+/// Parses an animation frame (`P[c:0%](255,0,0), P[5] (0,0,255)`). Positions without `%`
+/// are returned as mm and flagged `false`.
+pub fn parse_frame(frame: &str) -> Result<Vec<(Pixel, bool)>, String> {
+    split_unquoted(frame, ',')
+        .into_iter()
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (target, color) = p
+                .strip_prefix("P[")
+                .and_then(|p| p.split_once(']'))
+                .ok_or_else(|| format!("Unsupported pixel {}", p))?;
+            let color = color
+                .trim()
+                .strip_prefix('(')
+                .and_then(|c| c.strip_suffix(')'))
+                .ok_or_else(|| format!("Missing channel values in {}", p))?;
+            let (target, percent) = parse_target(target)?;
+            Ok((
+                Pixel {
+                    target,
+                    color: parse_color(color)?,
+                },
+                percent,
+            ))
+        })
+        .collect()
+}
+
+/// Settings and frames of an animation, canned animations are expanded and generators run
+pub fn animation_frames(config: &KllConfig, name: &str) -> Result<(String, Vec<String>), String> {
+    let animation = match config.animations.as_ref().and_then(|a| a.get(name)) {
+        Some(animation) => animation.clone(),
+        None => match config.canned.as_ref().and_then(|c| c.get(name)) {
+            Some(canned) => expand_canned(name, canned)?.0,
+            None => return Err(format!("Unknown animation {}", name)),
+        },
+    };
+    let leds = config.leds.clone().unwrap_or_default();
+    let frames = match &animation.generator {
+        Some(generator) => generate_frames(generator, &leds),
+        None => animation.frames,
+    };
+    Ok((animation.settings, frames))
+}
+
+/// Positions of the LEDs along an axis, in percent and in mm from the first one
+fn axis_positions(coords: &[f32]) -> Vec<(f32, f32)> {
+    let min = coords.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = coords.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    coords
+        .iter()
+        .map(|c| {
+            let percent = if max > min {
+                (c - min) / (max - min) * 100.
+            } else {
+                0.
+            };
+            (percent, c - min)
+        })
+        .collect()
+}
+
+fn lerp(a: [u8; 3], b: [u8; 3], t: f32) -> [u8; 3] {
+    [0, 1, 2].map(|i| (a[i] as f32 + (b[i] as f32 - a[i] as f32) * t).round() as u8)
+}
+
+/// Applies the column (or row) pixels of a frame. With `pfunc:interp` the LEDs between two
+/// pixels get a gradient, otherwise each pixel only sets the LEDs closest to it.
+fn apply_stops(
+    state: &mut [[u8; 3]],
+    positions: &[f32],
+    stops: &mut [(f32, [u8; 3])],
+    interp: bool,
+) {
+    if stops.is_empty() {
+        return;
+    }
+    stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    if interp {
+        for (led, p) in positions.iter().enumerate() {
+            let before = stops.iter().rev().find(|(pos, _)| pos <= p);
+            let after = stops.iter().find(|(pos, _)| pos >= p);
+            if let (Some((a, ca)), Some((b, cb))) = (before, after) {
+                state[led] = if b > a {
+                    lerp(*ca, *cb, (p - a) / (b - a))
+                } else {
+                    *ca
+                };
+            }
+        }
+        return;
+    }
+
+    for (pos, color) in stops.iter() {
+        let closest = positions
+            .iter()
+            .map(|p| (p - pos).abs())
+            .fold(f32::INFINITY, f32::min);
+        // Pixels outside of the board don't light anything
+        if !(-0.5..=100.5).contains(pos) {
+            continue;
+        }
+        for (led, p) in positions.iter().enumerate() {
+            if (p - pos).abs() <= closest + 0.5 {
+                state[led] = *color;
+            }
+        }
+    }
+}
+
+/// Simulates an animation over the LEDs. Frames are drawn over the previous one unless the
+/// settings contain `replace:clear`.
+pub fn simulate(settings: &str, frames: &[String], leds: &[Led]) -> Result<Preview, String> {
+    let settings = settings.split(',').map(|s| s.trim()).collect::<Vec<_>>();
+    let clear = settings.contains(&"replace:clear");
+    let interp = settings.contains(&"pfunc:interp");
+
+    let columns = axis_positions(&leds.iter().map(|l| l.x).collect::<Vec<_>>());
+    let rows = axis_positions(&leds.iter().map(|l| l.y).collect::<Vec<_>>());
+    let index = leds
+        .iter()
+        .enumerate()
+        .map(|(i, l)| (l.id, i))
+        .collect::<HashMap<_, _>>();
+
+    let mut state = vec![[0, 0, 0]; leds.len()];
+    let mut preview = Vec::new();
+    for (i, frame) in frames.iter().filter(|f| !f.starts_with('#')).enumerate() {
+        let pixels = parse_frame(frame).map_err(|e| format!("Frame {}: {}", i + 1, e))?;
+        if clear {
+            state.fill([0, 0, 0]);
+        }
+
+        let (mut column_stops, mut row_stops) = (Vec::new(), Vec::new());
+        let (mut column_mm, mut row_mm) = (Vec::new(), Vec::new());
+        for (pixel, percent) in pixels {
+            match (pixel.target, percent) {
+                (PixelTarget::Column(pos), true) => column_stops.push((pos, pixel.color)),
+                (PixelTarget::Column(pos), false) => column_mm.push((pos, pixel.color)),
+                (PixelTarget::Row(pos), true) => row_stops.push((pos, pixel.color)),
+                (PixelTarget::Row(pos), false) => row_mm.push((pos, pixel.color)),
+                (PixelTarget::Ids(ids), _) => {
+                    for id in ids {
+                        if let Some(led) = index.get(&id) {
+                            state[*led] = pixel.color;
+                        }
+                    }
+                }
+            }
+        }
+
+        let percent = |p: &[(f32, f32)]| p.iter().map(|p| p.0).collect::<Vec<_>>();
+        let mm = |p: &[(f32, f32)]| p.iter().map(|p| p.1).collect::<Vec<_>>();
+        apply_stops(&mut state, &percent(&columns), &mut column_stops, interp);
+        apply_stops(&mut state, &mm(&columns), &mut column_mm, interp);
+        apply_stops(&mut state, &percent(&rows), &mut row_stops, interp);
+        apply_stops(&mut state, &mm(&rows), &mut row_mm, interp);
+        preview.push(state.clone());
+    }
+
+    Ok(Preview {
+        leds: leds.iter().map(|l| l.id).collect(),
+        frames: preview,
+    })
+}
+
+/// Pixels per layout unit in the animated preview
+const GIF_UNIT_PX: f32 = 4.0;
+/// Largest preview image, width times height
+pub const GIF_MAX_PIXELS: usize = 1 << 20;
+/// Largest preview animation, pixels of all frames together
+pub const GIF_MAX_TOTAL_PIXELS: usize = 1 << 26;
+/// Longest time between frames, GIF delays are 16 bit hundredths of a second
+pub const GIF_MAX_DELAY_MS: u32 = u16::MAX as u32 * 10;
+const GIF_MARGIN_PX: usize = 4;
+const GIF_LED_RADIUS_PX: i32 = 3;
+const BACKGROUND: u8 = 0;
+const KEY: u8 = 1;
+/// The rest of the palette is a 6x6x6 color cube
+const CUBE: u8 = 2;
+
+fn palette() -> Vec<u8> {
+    let mut palette = vec![32, 32, 32, 72, 72, 72];
+    for r in 0..6 {
+        for g in 0..6 {
+            for b in 0..6 {
+                palette.extend([r * 51, g * 51, b * 51]);
+            }
+        }
+    }
+    palette.resize(256 * 3, 0);
+    palette
+}
+
+fn color_index(color: [u8; 3]) -> u8 {
+    let [r, g, b] = color.map(|c| ((c as u16 * 5 + 127) / 255) as u8);
+    CUBE + r * 36 + g * 6 + b
+}
+
+/// LZW compressed GIF image data, in sub-blocks
+fn lzw_encode(pixels: &[u8]) -> Vec<u8> {
+    const CLEAR: u16 = 256;
+    const END: u16 = 257;
+
+    let mut bytes = Vec::new();
+    let (mut bits, mut nbits) = (0u32, 0);
+    let mut emit = |code: u16, size: u32, bytes: &mut Vec<u8>| {
+        bits |= (code as u32) << nbits;
+        nbits += size;
+        while nbits >= 8 {
+            bytes.push(bits as u8);
+            bits >>= 8;
+            nbits -= 8;
+        }
+    };
+
+    let mut table = HashMap::new();
+    let mut size = 9;
+    let mut next = END + 1;
+    emit(CLEAR, size, &mut bytes);
+
+    let mut current: Option<u16> = None;
+    for &p in pixels {
+        let Some(prefix) = current else {
+            current = Some(p as u16);
+            continue;
+        };
+        if let Some(&code) = table.get(&(prefix, p)) {
+            current = Some(code);
+            continue;
+        }
+
+        emit(prefix, size, &mut bytes);
+        if next < 4096 {
+            table.insert((prefix, p), next);
+            next += 1;
+            // The decoder adds its entry one code later, so the code size grows late
+            if next > 1 << size && size < 12 {
+                size += 1;
+            }
+        } else {
+            emit(CLEAR, size, &mut bytes);
+            table.clear();
+            size = 9;
+            next = END + 1;
+        }
+        current = Some(p as u16);
+    }
+    if let Some(code) = current {
+        emit(code, size, &mut bytes);
+    }
+    emit(END, size, &mut bytes);
+    emit(0, 7, &mut bytes);
+
+    let mut blocks = vec![8];
+    for chunk in bytes.chunks(255) {
+        blocks.push(chunk.len() as u8);
+        blocks.extend_from_slice(chunk);
+    }
+    blocks.push(0);
+    blocks
+}
+
+/// Renders a simulated animation as a looping GIF, the LEDs are drawn over the keys of the
+/// layout. `delay_ms` is the time between frames. Fails if the layout or animation is too
+/// large to draw, or the delay can't be encoded.
+pub fn render_gif(config: &KllConfig, preview: &Preview, delay_ms: u32) -> Result<Vec<u8>, String> {
+    if delay_ms > GIF_MAX_DELAY_MS {
+        return Err(format!(
+            "Delay of {} ms is longer than {} ms",
+            delay_ms, GIF_MAX_DELAY_MS
+        ));
+    }
+
+    let keys = config
+        .matrix
+        .iter()
+        .filter_map(|k| Some((k.x?, k.y?, k.w.unwrap_or(4.), k.h.unwrap_or(4.))))
+        .collect::<Vec<_>>();
+    let leds = config.leds.clone().unwrap_or_default();
+    let led_points = preview
+        .leds
+        .iter()
+        .filter_map(|id| leds.iter().find(|l| l.id == *id))
+        .map(|l| (l.x / LED_MM_PER_UNIT + 2., l.y / LED_MM_PER_UNIT + 2.))
+        .collect::<Vec<_>>();
+
+    let px = |units: f32| ((units * GIF_UNIT_PX).round() as usize).saturating_add(GIF_MARGIN_PX);
+    let width = keys
+        .iter()
+        .map(|(x, _, w, _)| px(x + w))
+        .chain(led_points.iter().map(|(x, _)| px(*x)))
+        .fold(0, usize::max)
+        .saturating_add(GIF_MARGIN_PX);
+    let height = keys
+        .iter()
+        .map(|(_, y, _, h)| px(y + h))
+        .chain(led_points.iter().map(|(_, y)| px(*y)))
+        .fold(0, usize::max)
+        .saturating_add(GIF_MARGIN_PX);
+    if width > u16::MAX as usize || height > u16::MAX as usize || width * height > GIF_MAX_PIXELS {
+        return Err(format!(
+            "Preview of {}x{} pixels is larger than {} pixels",
+            width, height, GIF_MAX_PIXELS
+        ));
+    }
+    let total = (width * height).saturating_mul(preview.frames.len());
+    if total > GIF_MAX_TOTAL_PIXELS {
+        return Err(format!(
+            "Preview of {} frames of {}x{} pixels is larger than {} pixels",
+            preview.frames.len(),
+            width,
+            height,
+            GIF_MAX_TOTAL_PIXELS
+        ));
+    }
+
+    let mut background = vec![BACKGROUND; width * height];
+    for (x, y, w, h) in keys {
+        for row in px(y) + 1..px(y + h).saturating_sub(1) {
+            for col in px(x) + 1..px(x + w).saturating_sub(1) {
+                background[row * width + col] = KEY;
+            }
+        }
+    }
+
+    let mut gif = b"GIF89a".to_vec();
+    gif.extend((width as u16).to_le_bytes());
+    gif.extend((height as u16).to_le_bytes());
+    gif.extend([0xf7, BACKGROUND, 0]);
+    gif.extend(palette());
+    // Loop forever
+    gif.extend(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00");
+
+    for frame in preview.frames.iter() {
+        let mut image = background.clone();
+        for ((x, y), color) in led_points.iter().zip(frame.iter()) {
+            let (cx, cy) = (px(*x) as i32, px(*y) as i32);
+            for dy in -GIF_LED_RADIUS_PX..=GIF_LED_RADIUS_PX {
+                for dx in -GIF_LED_RADIUS_PX..=GIF_LED_RADIUS_PX {
+                    let (col, row) = (cx + dx, cy + dy);
+                    if dx * dx + dy * dy <= GIF_LED_RADIUS_PX * GIF_LED_RADIUS_PX
+                        && (0..width as i32).contains(&col)
+                        && (0..height as i32).contains(&row)
+                    {
+                        image[row as usize * width + col as usize] = color_index(*color);
+                    }
+                }
+            }
+        }
+
+        gif.extend([0x21, 0xf9, 0x04, 0x00]);
+        gif.extend(((delay_ms / 10) as u16).to_le_bytes());
+        gif.extend([0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00]);
+        gif.extend((width as u16).to_le_bytes());
+        gif.extend((height as u16).to_le_bytes());
+        gif.push(0x00);
+        gif.extend(lzw_encode(&image));
+    }
+    gif.push(0x3b);
+    Ok(gif)
+}
@@ -0,0 +1,133 @@
+use crate::kll::*;
+
+use std::fmt::Write;
+
+/// Pixels per layout unit. Layouts use 4 units per 1u key.
+const UNIT_PX: f32 = 15.0;
+/// LED coordinates are in mm, 19.05 mm per 1u key.
+pub(crate) const LED_MM_PER_UNIT: f32 = 19.05 / 4.0;
+const MARGIN_PX: f32 = 10.0;
+const KEY_PAD_PX: f32 = 2.0;
+const LED_RADIUS_PX: f32 = 4.0;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Label shown for a key on a layer, falling back to the bound key names.
+fn key_label(action: &KeyAction) -> String {
+    match &action.label {
+        Some(label) if !label.is_empty() => label.clone(),
+        _ => action
+            .combos()
+            .iter()
+            .map(|combo| {
+                combo
+                    .iter()
+                    .map(|k| k.trim_start_matches("#:"))
+                    .collect::<Vec<_>>()
+                    .join("+")
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+/// Renders a single layer of a layout as an SVG image. Keys without a position are skipped,
+/// keys without a binding on the requested layer are drawn blank.
+pub fn render_svg(config: &KllConfig, layer: usize, leds: bool) -> String {
+    let keys = config
+        .matrix
+        .iter()
+        .filter_map(|k| Some((k, k.x?, k.y?, k.w.unwrap_or(4.), k.h.unwrap_or(4.))))
+        .collect::<Vec<_>>();
+    let led_points = match (&config.leds, leds) {
+        (Some(l), true) => l
+            .iter()
+            .map(|led| {
+                (
+                    led.id,
+                    led.x / LED_MM_PER_UNIT + 2.,
+                    led.y / LED_MM_PER_UNIT + 2.,
+                )
+            })
+            .collect::<Vec<_>>(),
+        _ => Vec::new(),
+    };
+
+    let width = keys
+        .iter()
+        .map(|(_, x, _, w, _)| x + w)
+        .chain(led_points.iter().map(|(_, x, _)| *x))
+        .fold(0., f32::max);
+    let height = keys
+        .iter()
+        .map(|(_, _, y, _, h)| y + h)
+        .chain(led_points.iter().map(|(_, _, y)| *y))
+        .fold(0., f32::max);
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="10">"#,
+        w = width * UNIT_PX + 2. * MARGIN_PX,
+        h = height * UNIT_PX + 2. * MARGIN_PX,
+    );
+    let _ = writeln!(
+        svg,
+        r#"<title>{} {} - Layer {}</title>"#,
+        escape(&config.header.name),
+        escape(&config.header.layout),
+        layer
+    );
+
+    for (key, x, y, w, h) in keys {
+        let px = x * UNIT_PX + MARGIN_PX + KEY_PAD_PX;
+        let py = y * UNIT_PX + MARGIN_PX + KEY_PAD_PX;
+        let pw = w * UNIT_PX - 2. * KEY_PAD_PX;
+        let ph = h * UNIT_PX - 2. * KEY_PAD_PX;
+        let action = key.layers.get(&layer);
+        let fill = if action.is_some() {
+            "#f4f4f4"
+        } else {
+            "#d0d0d0"
+        };
+
+        let _ = writeln!(
+            svg,
+            r##"<rect x="{}" y="{}" width="{}" height="{}" rx="4" fill="{}" stroke="#404040" data-code="{}"/>"##,
+            px,
+            py,
+            pw,
+            ph,
+            fill,
+            escape(&key.code)
+        );
+        if let Some(action) = action {
+            let _ = writeln!(
+                svg,
+                r#"<text x="{}" y="{}" text-anchor="middle" dominant-baseline="middle">{}</text>"#,
+                px + pw / 2.,
+                py + ph / 2.,
+                escape(&key_label(action))
+            );
+        }
+    }
+
+    for (id, x, y) in led_points {
+        let _ = writeln!(
+            svg,
+            r##"<circle cx="{}" cy="{}" r="{}" fill="#ffcc00" fill-opacity="0.6" data-led="{}"/>"##,
+            x * UNIT_PX + MARGIN_PX,
+            y * UNIT_PX + MARGIN_PX,
+            LED_RADIUS_PX,
+            id
+        );
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
@@ -0,0 +1,98 @@
+use crate::firmware::FIRMWARE_PROFILES;
+use crate::kll::MatrixKey;
+
+use std::path::Path;
+
+/// Scan code differences between the layouts in `./layouts` (which follow the latest firmware)
+/// and an older firmware version of a board.
+pub struct ScanCodeMap {
+    pub board: &'static str,
+    pub version: &'static str,
+    /// Layout describing the firmware's default map, used instead of the header's `Base`
+    pub base: Option<&'static str>,
+    /// Scan codes that moved, as (latest, firmware) pairs
+    pub codes: &'static [(&'static str, &'static str)],
+}
+
+pub const SCAN_CODE_MAPS: &[ScanCodeMap] = &[
+    // Between LTS and Latest the scancode mapping for White Fox changed. Previously there was
+    //  a single all encompassing map, now there are a number of smaller ones that have
+    //  different (sensible) default scancode mappings.
+    // Only the default map differs, no scan code moved: the LTS configurator bound each
+    //  variant's keys to the `AllBlank` key with the same code (see tests/web_lts/WhiteFox-*).
+    ScanCodeMap {
+        board: "whitefox",
+        version: "lts",
+        base: Some("AllBlank"),
+        codes: &[],
+    },
+];
+
+pub fn scan_code_map(board: &str, version: &str) -> Option<&'static ScanCodeMap> {
+    let board = board.to_lowercase();
+    SCAN_CODE_MAPS
+        .iter()
+        .find(|m| m.board == board && m.version == version)
+}
+
+impl ScanCodeMap {
+    /// Latest scan code to the firmware's scan code
+    pub fn to_firmware(&self, code: &str) -> String {
+        self.codes
+            .iter()
+            .find(|(latest, _)| latest.eq_ignore_ascii_case(code))
+            .map_or(code, |(_, firmware)| firmware)
+            .to_string()
+    }
+
+    /// Firmware scan code back to the latest scan code
+    pub fn to_latest(&self, code: &str) -> String {
+        self.codes
+            .iter()
+            .find(|(_, firmware)| firmware.eq_ignore_ascii_case(code))
+            .map_or(code, |(latest, _)| latest)
+            .to_string()
+    }
+}
+
+/// Converts the scan codes of a matrix from one firmware version of a board to another.
+/// Versions without an entry in `SCAN_CODE_MAPS` use the latest scan codes. Fails for an
+/// unknown scan code version or a board and `Base` without a base layout.
+pub fn migrate_matrix(
+    board: &str,
+    base: &str,
+    matrix: &[MatrixKey],
+    from: &str,
+    to: &str,
+) -> Result<Vec<MatrixKey>, String> {
+    for version in [from, to] {
+        if !FIRMWARE_PROFILES.iter().any(|p| p.scan_codes == version) {
+            return Err(format!("Unknown scan code version {}", version));
+        }
+    }
+    let base_file = format!("./layouts/{}-{}.json", board.replace(' ', "_"), base);
+    if [board, base]
+        .iter()
+        .any(|s| s.is_empty() || s.contains(['/', '\\', '.']))
+        || !Path::new(&base_file).is_file()
+    {
+        return Err(format!("Unknown board {} {}", board, base));
+    }
+
+    let from = scan_code_map(board, from);
+    let to = scan_code_map(board, to);
+
+    Ok(matrix
+        .iter()
+        .map(|key| {
+            let mut key = key.clone();
+            if let Some(from) = from {
+                key.code = from.to_latest(&key.code);
+            }
+            if let Some(to) = to {
+                key.code = to.to_firmware(&key.code);
+            }
+            key
+        })
+        .collect())
+}
@@ -1,6 +1,14 @@
+use crate::build::ContainerImage;
+
+use indexmap::IndexMap;
 use maplit::hashmap;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::HashMap;
 
+pub const CONFIG_DB_SCHEMA: &str = include_str!("../schema/config.sqlite");
+pub const TAGS_DB_SCHEMA: &str = include_str!("../schema/tags.sqlite");
+
 // Static version mapping - retained for potential future use
 #[allow(dead_code)]
 pub fn version_map() -> HashMap<String, String> {
@@ -12,3 +20,251 @@ pub fn version_map() -> HashMap<String, String> {
         "v0.5.0".to_string() => "controller-050".to_string(),
     }
 }
+
+/// A row of the `Versions` table
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VersionMap {
+    pub name: String,
+    pub channel: String,
+    pub container: String,
+    pub git_tag: String,
+}
+
+/// Fields of a `Versions` entry, only `channel` is needed to re-channel an existing one
+#[derive(Default, Deserialize)]
+pub struct VersionUpdate {
+    pub channel: Option<String>,
+    pub container: Option<String>,
+    pub git_tag: Option<String>,
+}
+
+/// An entry of the `Versions` table whose container is in compose.yaml
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub container: String,
+    pub channel: String,
+    pub git_tag: String,
+    pub info: Option<ReleaseInfo>,
+    pub image: ContainerImage,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReleaseInfo {
+    pub commit: u16,
+    pub date: String,
+    pub hash: String,
+    pub bcd: String,
+    pub notes: String,
+}
+
+/// A commit of the changelog between two versions
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangelogEntry {
+    pub hash: String,
+    pub date: String,
+    pub summary: String,
+}
+
+/// Splits a changelog range, `lts..v0.5.7`
+pub fn split_range(range: &str) -> Option<(&str, &str)> {
+    let (from, to) = range.split_once("..")?;
+    if from.is_empty() || to.is_empty() || to.starts_with('.') {
+        return None;
+    }
+    Some((from, to))
+}
+
+/// Parses `git log --pretty=tformat:%H%x09%ai%x09%s`
+pub fn parse_changelog(out: &str) -> Vec<ChangelogEntry> {
+    out.lines()
+        .filter_map(|l| {
+            let mut parts = l.splitn(3, '\t');
+            Some(ChangelogEntry {
+                hash: parts.next()?.to_string(),
+                date: parts.next()?.to_string(),
+                summary: parts.next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Numeric parts of a tag, `v0.5.7` -> `[0, 5, 7]`
+fn tag_order(tag: &str) -> Vec<u32> {
+    tag.trim_start_matches('v')
+        .split('.')
+        .map(|p| p.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Finds the version to build for a request's `env`: a version name (`latest`, `v0.5.6`),
+/// a git tag, or a channel (`stable`), which picks the newest tag of that channel. Versions
+/// without an image are skipped.
+pub fn resolve_version<'a>(
+    versions: &'a HashMap<String, VersionInfo>,
+    env: &str,
+) -> Option<(&'a String, &'a VersionInfo)> {
+    if let Some(version) = versions
+        .get_key_value(env)
+        .filter(|(_, v)| v.image.available)
+    {
+        return Some(version);
+    }
+    let versions = versions.iter().filter(|(_, v)| v.image.available);
+    let newest = |matching: Vec<(&'a String, &'a VersionInfo)>| {
+        matching.into_iter().max_by(|a, b| {
+            tag_order(&a.1.git_tag)
+                .cmp(&tag_order(&b.1.git_tag))
+                .then(b.0.cmp(a.0))
+        })
+    };
+    newest(versions.clone().filter(|(_, v)| v.git_tag == env).collect())
+        .or_else(|| newest(versions.filter(|(_, v)| v.channel == env).collect()))
+}
+
+/// Creates the `Versions` and `Tags` tables. `Versions` is only seeded when it's created,
+/// afterwards it's managed through the admin API and retired versions stay retired.
+pub fn init_config_db(db: &Connection) -> rusqlite::Result<()> {
+    let exists: bool = db.query_row(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'Versions'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        db.execute_batch(CONFIG_DB_SCHEMA)?;
+    }
+    db.execute_batch(TAGS_DB_SCHEMA)
+}
+
+pub fn version_rows(db: &Connection) -> Vec<VersionMap> {
+    let mut stmt = db.prepare("SELECT * FROM Versions").unwrap();
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(VersionMap {
+                name: row.get(0)?,
+                channel: row.get(1)?,
+                container: row.get(2)?,
+                git_tag: row.get(3)?,
+            })
+        })
+        .unwrap();
+    rows.map(|r| r.unwrap()).collect()
+}
+
+/// Adds a version, or updates the fields given for an existing one. Returns `None` when a
+/// new version is missing a field.
+pub fn update_version(
+    db: &Connection,
+    name: &str,
+    update: VersionUpdate,
+) -> rusqlite::Result<Option<VersionMap>> {
+    let existing = version_rows(db).into_iter().find(|v| v.name == name);
+    let version = match (existing, update) {
+        (Some(v), update) => VersionMap {
+            name: v.name,
+            channel: update.channel.unwrap_or(v.channel),
+            container: update.container.unwrap_or(v.container),
+            git_tag: update.git_tag.unwrap_or(v.git_tag),
+        },
+        (
+            None,
+            VersionUpdate {
+                channel: Some(channel),
+                container: Some(container),
+                git_tag: Some(git_tag),
+            },
+        ) => VersionMap {
+            name: name.to_string(),
+            channel,
+            container,
+            git_tag,
+        },
+        (None, _) => return Ok(None),
+    };
+
+    db.execute(
+        "INSERT OR REPLACE INTO Versions (name, channel, container, git_tag) VALUES (?1, ?2, ?3, ?4)",
+        [&version.name, &version.channel, &version.container, &version.git_tag],
+    )?;
+    Ok(Some(version))
+}
+
+/// Removes a version, returns whether it existed
+pub fn retire_version(db: &Connection, name: &str) -> rusqlite::Result<bool> {
+    let removed = db.execute("DELETE FROM Versions WHERE name = ?1", [name])?;
+    Ok(removed > 0)
+}
+
+/// Tag metadata stored by the last successful refresh
+pub fn cached_tags(db: &Connection) -> IndexMap<String, ReleaseInfo> {
+    let mut stmt = db
+        .prepare("SELECT tag, commit_count, date, hash, bcd, notes FROM Tags")
+        .unwrap();
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                ReleaseInfo {
+                    commit: row.get(1)?,
+                    date: row.get(2)?,
+                    hash: row.get(3)?,
+                    bcd: row.get(4)?,
+                    notes: row.get(5)?,
+                },
+            ))
+        })
+        .unwrap();
+    rows.map(|r| r.unwrap()).collect()
+}
+
+/// Stores tag metadata, replacing earlier entries of the same tags
+pub fn store_tags(db: &Connection, tags: &IndexMap<String, ReleaseInfo>) {
+    for (tag, info) in tags.iter() {
+        let stored = db.execute(
+            "INSERT OR REPLACE INTO Tags (tag, commit_count, date, hash, bcd, notes) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![tag, info.commit, info.date, info.hash, info.bcd, info.notes],
+        );
+        if let Err(e) = stored {
+            tracing::warn!("Could not cache tag {}: {}", tag, e);
+        }
+    }
+}
+
+/// Versions whose container is in compose.yaml, with their tag metadata and image state
+pub fn version_infos(
+    rows: Vec<VersionMap>,
+    tags: &IndexMap<String, ReleaseInfo>,
+    images: &[ContainerImage],
+) -> HashMap<String, VersionInfo> {
+    rows.into_iter()
+        .filter_map(|v| {
+            let image = images.iter().find(|i| i.service == v.container)?.clone();
+            Some((
+                v.name,
+                VersionInfo {
+                    container: v.container,
+                    channel: v.channel,
+                    git_tag: v.git_tag.clone(),
+                    info: tags.get(&v.git_tag).cloned(),
+                    image,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Checks an `Authorization: Bearer <token>` header against the admin token, in constant
+/// time for tokens of the same length. An empty token never matches.
+pub fn admin_authorized(token: &str, authorization: Option<&str>) -> bool {
+    let Some(auth) = authorization.and_then(|h| h.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    let (token, auth) = (token.as_bytes(), auth.as_bytes());
+    if token.is_empty() || token.len() != auth.len() {
+        return false;
+    }
+    let diff = token
+        .iter()
+        .zip(auth)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    diff == 0
+}
@@ -1,5 +1,22 @@
+use kiisrv::animation::*;
+use kiisrv::ast::*;
+use kiisrv::build::*;
+use kiisrv::firmware::*;
+use kiisrv::hid::*;
+use kiisrv::import::*;
 use kiisrv::kll::*;
+use kiisrv::layouts::*;
+use kiisrv::limits::*;
+use kiisrv::merge::*;
+use kiisrv::preview::*;
+use kiisrv::render::*;
+use kiisrv::scancodes::*;
+use kiisrv::versions::*;
+
+use axum::http::{header, HeaderMap, StatusCode};
+use indexmap::IndexMap;
 use rstest::rstest;
+use rusqlite::Connection;
 use std::fs;
 
 #[rstest]
@@ -11,7 +28,8 @@ fn parse_layout(#[case] json_file: &str) {
         let contents = fs::read_to_string(filename).unwrap();
         serde_json::from_str(&contents).unwrap()
     };
-    assert!(true);
+    assert_eq!(config.header.name, "Kira");
+    assert!(!config.matrix.is_empty());
 }
 
 #[rstest]
@@ -43,7 +61,7 @@ fn generate_kll_latest(#[case] json_file: &str, #[case] kll_dir: &str) {
         serde_json::from_str(&contents).unwrap()
     };
 
-    let files = generate_kll(&config, false);
+    let files = generate_kll(&config, firmware_profile("latest"), false);
     for file in files {
         let kll_file = format!("{}/{}/{}", "tests/web_latest", kll_dir, file.name);
         println!("Comparing to {}", kll_file);
@@ -52,6 +70,29 @@ fn generate_kll_latest(#[case] json_file: &str, #[case] kll_dir: &str) {
     }
 }
 
+#[rstest]
+#[case("K-Type-Standard.json", "KType-Standard")]
+#[case("MD1.1-Alphabet.json", "MD1.1-Alphabet")]
+#[case("MD1-Standard.json", "MD1-Standard")]
+#[case("MDErgo1-Default.json", "MDErgo1-Default")]
+#[case("WhiteFox-Iso.json", "WhiteFox-Iso")]
+fn generate_kll_latest_changed(#[case] json_file: &str, #[case] kll_dir: &str) {
+    let filename = format!("{}/{}", "layouts", json_file);
+    println!("Parsing {}", filename);
+    let config: KllConfig = {
+        let contents = fs::read_to_string(filename).unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+
+    let files = generate_kll(&config, firmware_profile("latest"), true);
+    for file in files {
+        let kll_file = format!("{}/{}/{}", "tests/web_latest_changed", kll_dir, file.name);
+        println!("Comparing to {}", kll_file);
+        let kll = fs::read_to_string(kll_file).unwrap();
+        assert_eq!(file.content, kll);
+    }
+}
+
 #[rstest]
 #[case("K-Type-Standard.json", "KType-Standard")]
 #[case("K-Type-NoAnimations.json", "KType-NoAnimations")]
@@ -81,7 +122,7 @@ fn generate_kll_lts(#[case] json_file: &str, #[case] kll_dir: &str) {
         serde_json::from_str(&contents).unwrap()
     };
 
-    let files = generate_kll(&config, true);
+    let files = generate_kll(&config, firmware_profile("lts"), false);
     for file in files {
         let kll_file = format!("{}/{}/{}", "tests/web_lts", kll_dir, file.name);
         println!("Comparing to {}", kll_file);
@@ -90,4 +131,1383 @@ fn generate_kll_lts(#[case] json_file: &str, #[case] kll_dir: &str) {
     }
 }
 
+#[rstest]
+#[case("K-Type-Standard.json")]
+#[case("K-Type-NoAnimations.json")]
+#[case("MD1.1-Alphabet.json")]
+#[case("MD1.1-Hacker.json")]
+#[case("MD1.1-Standard.json")]
+#[case("MD1-Hacker.json")]
+#[case("MD1-Standard.json")]
+#[case("MDErgo1-Default.json")]
+#[case("WhiteFox-Aria.json")]
+#[case("WhiteFox-Iso.json")]
+#[case("WhiteFox-JackofAllTrades.json")]
+#[case("WhiteFox-TheTrueFox.json")]
+#[case("WhiteFox-Vanilla.json")]
+#[case("WhiteFox-Winkeyless.json")]
+fn import_kll_roundtrip(#[case] json_file: &str) {
+    let filename = format!("{}/{}", "layouts", json_file);
+    println!("Parsing {}", filename);
+    let config: KllConfig = {
+        let contents = fs::read_to_string(filename).unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+
+    let files = generate_kll(&config, firmware_profile("latest"), false)
+        .into_iter()
+        .map(|f| f.content)
+        .collect::<Vec<_>>();
+
+    let base_file = format!("{}/{}", "layouts", base_layout_file(&files[0]).unwrap());
+    println!("Importing against {}", base_file);
+    let base: KllConfig = {
+        let contents = fs::read_to_string(base_file).unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+
+    let imported = import_kll(&base, &files).unwrap();
+    let regenerated = generate_kll(&imported, firmware_profile("latest"), false)
+        .into_iter()
+        .map(|f| f.content)
+        .collect::<Vec<_>>();
+    assert_eq!(files, regenerated);
+}
+
+#[test]
+fn import_kll_errors() {
+    let base: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-StandardBlank.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+
+    let err = import_kll(&base, &["Name = \"MD1\";\nU\"A\" : U\"B\"".to_string()])
+        .err()
+        .unwrap();
+    assert_eq!(err.line, 2);
+
+    let err = import_kll(&base, &["\n\nU\"NOTAKEY\" : U\"B\";".to_string()])
+        .err()
+        .unwrap();
+    assert_eq!(err.line, 3);
+    assert_eq!(err.file, 0);
+}
+
+fn set_key(matrix: &mut [MatrixKey], code: &str, layer: usize, key: &str) {
+    let k = matrix.iter_mut().find(|k| k.code == code).unwrap();
+    k.layers.insert(
+        layer,
+        KeyAction {
+            key: key.to_string(),
+            ..Default::default()
+        },
+    );
+}
+
+fn get_key(matrix: &[MatrixKey], code: &str, layer: usize) -> Option<String> {
+    let k = matrix.iter().find(|k| k.code == code)?;
+    k.layers.get(&layer).map(|a| a.key.clone())
+}
+
+#[test]
+fn merge_layout_matrix() {
+    let old: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let old = old.matrix;
+    let code = |i: usize| old[i].code.clone();
+
+    let mut new = old.clone();
+    let mut user = old.clone();
+
+    // Base only
+    set_key(&mut new, &code(0), 0, "F13");
+    // User only
+    set_key(&mut user, &code(1), 1, "F14");
+    // Both, same value
+    set_key(&mut new, &code(2), 0, "F15");
+    set_key(&mut user, &code(2), 0, "F15");
+    // Both, different values
+    set_key(&mut new, &code(3), 0, "F16");
+    set_key(&mut user, &code(3), 0, "F17");
+
+    let merged = merge_matrix(&old, &new, &user);
+    assert_eq!(merged.matrix.len(), old.len());
+    assert_eq!(get_key(&merged.matrix, &code(0), 0).unwrap(), "F13");
+    assert_eq!(get_key(&merged.matrix, &code(1), 1).unwrap(), "F14");
+    assert_eq!(get_key(&merged.matrix, &code(2), 0).unwrap(), "F15");
+    assert_eq!(get_key(&merged.matrix, &code(3), 0).unwrap(), "F17");
+
+    assert_eq!(merged.conflicts.len(), 1);
+    let conflict = &merged.conflicts[0];
+    assert_eq!(conflict.code, code(3));
+    assert_eq!(conflict.layer, Some(0));
+    assert_eq!(conflict.new.as_ref().unwrap().key, "F16");
+    assert_eq!(conflict.user.as_ref().unwrap().key, "F17");
+}
+
+#[test]
+fn merge_extended_actions() {
+    let old: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let old = old.matrix;
+    let mut new = old.clone();
+    let mut user = old.clone();
+
+    // User only, same key with a combo, sequence, hold and release
+    let action = user[0].layers.get_mut(&0).unwrap();
+    action.combo = Some(vec!["LCTRL".to_string(), "C".to_string()]);
+    let action = user[1].layers.get_mut(&0).unwrap();
+    action.sequence = Some(vec![vec!["LSHIFT".to_string(), "H".to_string()]]);
+    let action = user[2].layers.get_mut(&0).unwrap();
+    action.hold = Some("LCTRL".to_string());
+    action.hold_time = Some(300);
+    let action = user[3].layers.get_mut(&0).unwrap();
+    action.release = Some("ESC".to_string());
+    // Base only
+    new[5].layers.get_mut(&0).unwrap().release = Some("ESC".to_string());
+    // Both, only the extended fields differ
+    new[4].layers.get_mut(&0).unwrap().hold = Some("LALT".to_string());
+    user[4].layers.get_mut(&0).unwrap().hold = Some("LGUI".to_string());
+
+    let merged = merge_matrix(&old, &new, &user);
+    for (merged_key, user_key) in merged.matrix.iter().zip(user.iter()).take(5) {
+        assert_eq!(merged_key.layers[&0], user_key.layers[&0]);
+    }
+    assert_eq!(merged.matrix[5].layers[&0], new[5].layers[&0]);
+
+    assert_eq!(merged.conflicts.len(), 1);
+    let conflict = &merged.conflicts[0];
+    assert_eq!(conflict.code, old[4].code);
+    assert_eq!(conflict.new.as_ref().unwrap().hold.as_deref(), Some("LALT"));
+    assert_eq!(
+        conflict.user.as_ref().unwrap().hold.as_deref(),
+        Some("LGUI")
+    );
+}
+
+#[test]
+fn merge_split_board() {
+    let old: KllConfig = {
+        let contents = fs::read_to_string("layouts/MDErgo1-Default.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let old = old.matrix;
+    // Both halves use the same scan codes
+    assert_eq!(old[0].code, old[38].code);
+
+    let mut new = old.clone();
+    let mut user = old.clone();
+    new[0].layers.get_mut(&0).unwrap().key = "F13".to_string();
+    user[38].layers.get_mut(&0).unwrap().key = "F14".to_string();
+
+    let merged = merge_matrix(&old, &new, &user);
+    assert_eq!(merged.matrix.len(), old.len());
+    assert!(merged.conflicts.is_empty());
+    assert_eq!(merged.matrix[0].layers[&0].key, "F13");
+    assert_eq!(merged.matrix[38].layers[&0].key, "F14");
+}
+
+#[rstest]
+#[case("K-Type-Standard.json", 87, 119)]
+#[case("MD1-Standard.json", 63, 0)]
+fn render_layout_svg(#[case] json_file: &str, #[case] keys: usize, #[case] leds: usize) {
+    let filename = format!("{}/{}", "layouts", json_file);
+    let config: KllConfig = {
+        let contents = fs::read_to_string(filename).unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+
+    let svg = render_svg(&config, 0, true);
+    assert!(svg.starts_with("<svg"));
+    assert!(svg.trim_end().ends_with("</svg>"));
+    assert_eq!(svg.matches("<rect").count(), keys);
+    assert_eq!(svg.matches("<text").count(), keys);
+    assert_eq!(svg.matches("<circle").count(), leds);
+    assert!(!svg.contains("<text x=\"0\""));
+
+    let svg = render_svg(&config, 1, false);
+    assert_eq!(svg.matches("<rect").count(), keys);
+    assert!(svg.matches("<text").count() < keys);
+    assert_eq!(svg.matches("<circle").count(), 0);
+}
+
+#[test]
+fn scan_code_translation() {
+    let map = ScanCodeMap {
+        board: "test",
+        version: "old",
+        base: None,
+        codes: &[("0x42", "0x3E"), ("0x3E", "0x42")],
+    };
+    assert_eq!(map.to_firmware("0x42"), "0x3E");
+    assert_eq!(map.to_firmware("0x3E"), "0x42");
+    assert_eq!(map.to_firmware("0x01"), "0x01");
+    assert_eq!(map.to_latest("0x3E"), "0x42");
+    assert_eq!(map.to_latest(&map.to_firmware("0x3E")), "0x3E");
+
+    let whitefox = scan_code_map("WhiteFox", "lts").unwrap();
+    assert_eq!(whitefox.base, Some("AllBlank"));
+    assert!(scan_code_map("WhiteFox", "latest").is_none());
+}
+
+#[test]
+fn scan_code_migrate_errors() {
+    assert!(migrate_matrix("WhiteFox", "VanillaBase", &[], "latest", "lts").is_ok());
+    assert!(migrate_matrix("WhiteFox", "VanillaBase", &[], "latest", "v9").is_err());
+    assert!(migrate_matrix("WhiteFox", "NoSuchBase", &[], "latest", "lts").is_err());
+    assert!(migrate_matrix("NoSuchBoard", "VanillaBase", &[], "latest", "lts").is_err());
+    assert!(migrate_matrix("WhiteFox", "../../etc/passwd", &[], "latest", "lts").is_err());
+}
+
+#[rstest]
+#[case("Azio-Fokal-Standard.json")]
+#[case("GeminiDuskDawn-Standard.json")]
+#[case("K-Type-Standard.json")]
+#[case("Kira-Standard.json")]
+#[case("MD1.1-Alphabet.json")]
+#[case("MD1-Hacker.json")]
+#[case("MDErgo1-Default.json")]
+#[case("WhiteFox-TheTrueFox.json")]
+fn validate_layout_keys(#[case] json_file: &str) {
+    let filename = format!("{}/{}", "layouts", json_file);
+    let config: KllConfig = {
+        let contents = fs::read_to_string(filename).unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+
+    let errors = validate_keys(&config);
+    assert!(errors.is_empty(), "{:?}", errors);
+}
+
+#[test]
+fn key_suggestions() {
+    assert_eq!(lookup_key("LBRACE"), Some(0x2F));
+    assert_eq!(lookup_key("lbrace"), lookup_key("["));
+    assert_eq!(lookup_key("CONS:PAUSE PLAY"), Some(0xCD));
+    assert_eq!(lookup_key("SYS:WAKE UP"), Some(0x83));
+    assert_eq!(lookup_key("LBRAC"), None);
+
+    assert_eq!(suggest_key("LBRAC").as_deref(), Some("LBRACE"));
+    assert_eq!(
+        suggest_key("CONS:VOLUMUP").as_deref(),
+        Some("CONS:VOLUMEUP")
+    );
+    assert_eq!(suggest_key("XYZZY"), None);
+}
+
+#[test]
+fn generate_kll_triggers() {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let trigger = |action: &str, _type: &str| Trigger {
+        action: action.to_string(),
+        label: action.to_string(),
+        _type: _type.to_string(),
+    };
+    let mut triggers = IndexMap::new();
+    triggers.insert(0, trigger("rainbow", "animation"));
+    triggers.insert(3, trigger("1", "layer"));
+    config.matrix[0].triggers = Some(triggers);
+    let mut triggers = IndexMap::new();
+    triggers.insert(0, trigger("layerShift( 2 )", "layer"));
+    config.matrix[1].triggers = Some(triggers);
+
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    assert_eq!(files.len(), 4);
+    assert!(files[0]
+        .content
+        .contains("U\"ESC\" :+ A[rainbow];\nU\"1\" :+ layerShift( 2 );\n"));
+    assert!(files[3].content.contains("U\"ESC\" :+ Layer[1];"));
+
+    // Triggers are dropped for LTS
+    let files = generate_kll(&config, firmware_profile("lts"), false);
+    assert_eq!(files.len(), 3);
+    assert!(!files[0].content.contains(":+"));
+
+    // And survive an import
+    let files = generate_kll(&config, firmware_profile("latest"), false)
+        .into_iter()
+        .map(|f| f.content)
+        .collect::<Vec<_>>();
+    let base: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-StandardBlank.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let imported = import_kll(&base, &files).unwrap();
+    let regenerated = generate_kll(&imported, firmware_profile("latest"), false)
+        .into_iter()
+        .map(|f| f.content)
+        .collect::<Vec<_>>();
+    assert_eq!(files, regenerated);
+}
+
+#[test]
+fn expand_canned_animations() {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/KType-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    assert!(!files[0].content.contains("A[miami_wave]"));
+
+    let canned = config
+        .canned
+        .as_mut()
+        .unwrap()
+        .get_mut("miami_wave")
+        .unwrap();
+    let (animation, _) = expand_canned("miami_wave", canned).unwrap();
+    assert!(animation.settings.starts_with("framedelay:3,"));
+
+    canned.enabled = Some(true);
+    canned.configurable[0].value = Some(serde_json::json!("warp"));
+    canned.custom_kll = Some("# speed ${speed}".to_string());
+    let (animation, custom_kll) = expand_canned("miami_wave", canned).unwrap();
+    assert!(animation.settings.starts_with("framedelay:1,"));
+    assert_eq!(custom_kll.as_deref(), Some("# speed 1"));
+    assert!(validate_canned(&config).is_empty());
+
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    assert!(files[0]
+        .content
+        .contains("A[miami_wave] <= framedelay:1, framestretch"));
+    assert!(files[0].content.contains("A[miami_wave, 1] <= "));
+    assert!(files[0].content.contains("# speed 1\n"));
+
+    let canned = config
+        .canned
+        .as_mut()
+        .unwrap()
+        .get_mut("miami_wave")
+        .unwrap();
+    canned.configurable[0].value = Some(serde_json::json!(7));
+    assert!(expand_canned("miami_wave", canned).is_err());
+    assert_eq!(validate_canned(&config).len(), 1);
+}
+
+#[test]
+fn expand_canned_placeholders() {
+    let config: KllConfig = {
+        let contents = fs::read_to_string("layouts/KType-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let canned = config.canned.as_ref().unwrap();
+    let expand = |name: &str| expand_canned(name, &canned[name]).unwrap();
+
+    let (animation, _) = expand("single_color");
+    assert_eq!(
+        animation.frames,
+        ["P[c:0%](255,255,255), P[c:100%](255,255,255)"]
+    );
+    let (animation, _) = expand("custom_wave");
+    assert!(animation.frames[0].ends_with("P[c:1%] (0,255,0),   P[c:25%](255,255,0), P[c:50%](255,255,255),   P[c:75%](127,0,255), P[c:100%](0,0,255)"));
+
+    // Fading to black, starting at (255,0,0)
+    let (animation, custom_kll) = expand("fingerprints");
+    assert_eq!(animation.frames[2], "P[r:i,c:i](230,0,0)");
+    assert_eq!(animation.frames[5], "P[r:i,c:i](77,0,0)");
+    assert_eq!(
+        custom_kll.as_deref(),
+        Some("S[0x00-0x5F] :+ A[fingerprints](start);")
+    );
+
+    // Blending from (255,0,0) to (255,255,255)
+    let (animation, _) = expand("fingerprints_two_tone");
+    assert_eq!(animation.frames[4], "P[r:i,c:i](255,77,77)");
+
+    let mut canned = canned["single_color"].clone();
+    canned.frames = vec!["P[c:0%](${colour})".to_string()];
+    assert!(expand_canned("single_color", &canned).is_err());
+    canned.frames = vec!["P[c:0%](${color!2})".to_string()];
+    assert!(expand_canned("single_color", &canned).is_err());
+    canned.frames = vec!["P[c:0%](${color)".to_string()];
+    assert!(expand_canned("single_color", &canned).is_err());
+    canned.configurable[0].value = Some(serde_json::json!({ "h": 0 }));
+    canned.frames = vec!["P[c:0%](${color})".to_string()];
+    assert!(expand_canned("single_color", &canned).is_err());
+}
+
+#[rstest]
+#[case("layouts/KType-Standard.json")]
+#[case("layouts/Kira-Standard.json")]
+#[case("layouts/GeminiDuskDawn-Standard.json")]
+fn expand_canned_presets(#[case] layout: &str) {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string(layout).unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    for (name, canned) in config.canned.as_mut().unwrap() {
+        let (animation, custom_kll) = expand_canned(name, canned).unwrap();
+        for text in animation
+            .frames
+            .iter()
+            .chain([&animation.settings])
+            .chain(&custom_kll)
+        {
+            assert!(
+                !text.contains('$') && !text.contains('{'),
+                "{}: {}",
+                name,
+                text
+            );
+        }
+        canned.enabled = Some(true);
+    }
+    assert!(validate_canned(&config).is_empty());
+
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    for name in config.canned.as_ref().unwrap().keys() {
+        assert!(files[0].content.contains(&format!("A[{}] <= ", name)));
+    }
+    assert!(files.iter().all(|f| !f.content.contains("${")));
+}
+
+#[rstest]
+#[case(Statement::Assignment { name: "Name".to_string(), value: "MD1".to_string() }, "Name = \"MD1\";")]
+#[case(Statement::Remap { trigger: "U\"A\"".to_string(), result: "U\"B\"".to_string() }, "U\"A\" : U\"B\";")]
+#[case(Statement::Trigger { trigger: "U\"A\"".to_string(), result: "A[wave]".to_string() }, "U\"A\" :+ A[wave];")]
+#[case(Statement::AnimationSettings { name: "wave".to_string(), settings: "loop".to_string() }, "A[wave] <= loop;")]
+#[case(Statement::AnimationFrame { name: "wave".to_string(), index: 2, frame: "P[1](255)".to_string() }, "A[wave, 2] <= P[1](255);")]
+#[case(Statement::PixelMap { index: 3, channels: "3:8".to_string(), scan_code: "S0x12".to_string() }, "P[3](3:8) : S0x12;")]
+#[case(Statement::PixelPosition { index: 3, position: "x:19.05, y:0".to_string() }, "P[3] <= x:19.05, y:0;")]
+#[case(Statement::Other("U\"A\" => U\"B\"".to_string()), "U\"A\" => U\"B\";")]
+fn print_parse_statement(#[case] statement: Statement, #[case] kll: &str) {
+    assert_eq!(statement.to_string(), kll);
+    let parsed = parse_statements(kll).unwrap();
+    assert_eq!(parsed.len(), 1);
+    assert_eq!(parsed[0].statement, statement);
+}
+
+#[test]
+fn generate_kll_structure() {
+    let config: KllConfig = {
+        let contents = fs::read_to_string("layouts/KType-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+
+    let (documents, _) = generate_kll_documents(&config, firmware_profile("latest"), false);
+    assert_eq!(documents[0].sections.len(), 5);
+    assert_eq!(
+        documents[0].sections[0][0],
+        Statement::Assignment {
+            name: "Name".to_string(),
+            value: "KType".to_string()
+        }
+    );
+    assert!(documents[0].sections[2]
+        .iter()
+        .all(|s| matches!(s, Statement::Remap { .. })));
+    assert_eq!(documents[1].sections.len(), 3);
+
+    // LTS comments out unsupported results instead of dropping them
+    let (documents, _) = generate_kll_documents(&config, firmware_profile("lts"), false);
+    assert!(documents[1].sections[1]
+        .iter()
+        .any(|s| matches!(s, Statement::Disabled(_))));
+
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    let (documents, _) = generate_kll_documents(&config, firmware_profile("latest"), false);
+    for (file, document) in files.iter().zip(documents) {
+        assert_eq!(file.name, document.name);
+        assert_eq!(file.content, document.to_string());
+    }
+}
+
+#[test]
+fn generate_kll_leds() {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/KType-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    // Stock geometry is defined by the firmware
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    assert!(!files[0].content.contains("P[1]"));
+
+    let leds = config.leds.as_mut().unwrap();
+    leds[1].x = 40.;
+    leds[1].channels = Some("16:8, 17:8, 18:8".to_string());
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    assert!(files[0].content.contains("P[1] <= x:0, y:0;\n"));
+    assert!(files[0]
+        .content
+        .contains("P[2](16:8, 17:8, 18:8) : S0x02;\nP[2] <= x:40, y:0;\n"));
+    assert!(!generate_kll(&config, firmware_profile("lts"), false)[0]
+        .content
+        .contains("P[2]"));
 
+    let files = files.into_iter().map(|f| f.content).collect::<Vec<_>>();
+    let base: KllConfig = {
+        let contents = fs::read_to_string("layouts/KType-Base.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let imported = import_kll(&base, &files).unwrap();
+    assert!(imported.leds == config.leds);
+    let regenerated = generate_kll(&imported, firmware_profile("latest"), false)
+        .into_iter()
+        .map(|f| f.content)
+        .collect::<Vec<_>>();
+    assert_eq!(files, regenerated);
+}
+
+#[test]
+fn generate_kll_by_scan_code() {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let expected = generate_kll(&config, firmware_profile("latest"), false)
+        .into_iter()
+        .map(|f| f.content)
+        .collect::<Vec<_>>();
+
+    // Key order doesn't matter
+    config.matrix.reverse();
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    for (file, expected) in files.iter().zip(expected.iter()) {
+        let mut lines = file.content.lines().collect::<Vec<_>>();
+        let mut expected = expected.lines().collect::<Vec<_>>();
+        lines.sort();
+        expected.sort();
+        assert_eq!(lines, expected);
+    }
+    assert!(unmatched_keys(&config, firmware_profile("latest")).is_empty());
+
+    // Unknown scan codes are reported and left out
+    config.matrix.truncate(config.matrix.len() - 1);
+    config.matrix[0].code = "0xFF".to_string();
+    let unmatched = unmatched_keys(&config, firmware_profile("latest"));
+    assert_eq!(unmatched.len(), 1);
+    assert_eq!(unmatched[0].index, 0);
+    assert_eq!(unmatched[0].code, "0xFF");
+    assert_eq!(
+        generate_kll(&config, firmware_profile("latest"), false).len(),
+        3
+    );
+}
+
+#[test]
+fn import_changed_only() {
+    let config: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let base: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-StandardBlank.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+
+    // Unchanged keys come from the base layout
+    let files = generate_kll(&config, firmware_profile("latest"), true)
+        .into_iter()
+        .map(|f| f.content)
+        .collect::<Vec<_>>();
+    let imported = import_kll(&base, &files).unwrap();
+    let full = |c: &KllConfig| {
+        generate_kll(c, firmware_profile("latest"), false)
+            .into_iter()
+            .map(|f| f.content)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(full(&imported), full(&config));
+}
+
+#[rstest]
+#[case(
+    r#"{"key": "C", "label": null, "combo": ["LCTRL", "C"]}"#,
+    r#"U"LCTRL" + U"C""#
+)]
+#[case(
+    r#"{"key": "H", "label": null, "sequence": [["LSHIFT", "H"], ["I"]]}"#,
+    r#"U"LSHIFT" + U"H", U"I""#
+)]
+#[case(
+    r##"{"key": "A", "label": null, "sequence": [["CONS:MUTE"], ["#:layerShift( 1 )"]]}"##,
+    r#"CONS"MUTE", layerShift( 1 )"#
+)]
+fn generate_kll_combos(#[case] action: &str, #[case] result: &str) {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let action: KeyAction = serde_json::from_str(action).unwrap();
+    config.matrix[0].layers.insert(1, action.clone());
+
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    assert!(files[1]
+        .content
+        .contains(&format!("U\"ESC\" : {};\n", result)));
+    assert!(validate_keys(&config).is_empty());
+
+    let imported = unformat_action(result);
+    assert_eq!(imported.combos(), action.combos());
+    assert_eq!(imported.key, action.combos()[0][0]);
+}
+
+#[test]
+fn generate_kll_scheduled() {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let esc = config.matrix[0].layers.get_mut(&0).unwrap();
+    esc.hold = Some("LCTRL".to_string());
+    let one = config.matrix[1].layers.get_mut(&1).unwrap();
+    one.release = Some("#:layerShift( 1 )".to_string());
+    one.hold = Some("F11".to_string());
+    one.hold_time = Some(300);
+
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    assert!(files[0]
+        .content
+        .contains("U\"ESC\"(H:200ms) : U\"LCTRL\";\nU\"ESC\"(R:200ms) : U\"ESC\";\n"));
+    assert!(files[1].content.contains(
+        "U\"1\"(H:300ms) : U\"F11\";\nU\"1\"(R:300ms) : U\"F1\";\nU\"1\"(R) : layerShift( 1 );\n"
+    ));
+    assert!(unsupported_actions(&config, firmware_profile("latest")).is_empty());
+    assert_eq!(
+        unsupported_actions(&config, firmware_profile("lts")).len(),
+        2
+    );
+
+    // Changed-only still emits scheduled keys that keep their base binding
+    let files = generate_kll(&config, firmware_profile("latest"), true);
+    assert!(files[0].content.contains("U\"ESC\"(H:200ms)"));
+
+    let files = generate_kll(&config, firmware_profile("latest"), false)
+        .into_iter()
+        .map(|f| f.content)
+        .collect::<Vec<_>>();
+    let base: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-StandardBlank.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let imported = import_kll(&base, &files).unwrap();
+    let one = imported.matrix[1].layers.get(&1).unwrap();
+    assert_eq!(one.key, "F1");
+    assert_eq!(one.hold.as_deref(), Some("F11"));
+    assert_eq!(one.hold_time, Some(300));
+    assert_eq!(one.release.as_deref(), Some("#:layerShift( 1 )"));
+    let regenerated = generate_kll(&imported, firmware_profile("latest"), false)
+        .into_iter()
+        .map(|f| f.content)
+        .collect::<Vec<_>>();
+    assert_eq!(files, regenerated);
+}
+
+#[test]
+fn validate_custom_blocks() {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let mut custom = IndexMap::new();
+    custom.insert(
+        0,
+        "# Custom\nU\"ESC\" : U\"A\";\nU\"Q\"(H:100ms) : U\"B\";\nU\"W\" :+ U\"C\";".to_string(),
+    );
+    custom.insert(1, "myDefine = \"1\";\nLayout = \"Other\";".to_string());
+    custom.insert(2, "U\"A\" : U\"B\"".to_string());
+    custom.insert(7, "U\"A\" : U\"B\";".to_string());
+    config.custom = Some(custom);
+
+    let documents = generate_kll_documents(&config, firmware_profile("latest"), false).0;
+    let issues = validate_custom(&config, &documents)
+        .into_iter()
+        .map(|i| (i.layer, i.line, i.error))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        issues,
+        vec![
+            (0, 2, false),
+            (0, 3, false),
+            (1, 2, true),
+            (2, 1, true),
+            (7, 0, false)
+        ]
+    );
+
+    config.custom = None;
+    assert!(validate_custom(&config, &documents).is_empty());
+}
+
+#[test]
+fn validate_custom_firmware() {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/K-Type-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let mut custom = IndexMap::new();
+    custom.insert(0, "A[rainbow_wave] <= loop;".to_string());
+    config.custom = Some(custom);
+
+    // Only firmware with animations generates the one being overridden
+    let latest = generate_kll_documents(&config, firmware_profile("latest"), false).0;
+    assert_eq!(validate_custom(&config, &latest).len(), 1);
+    let lts = generate_kll_documents(&config, firmware_profile("lts"), false).0;
+    assert!(validate_custom(&config, &lts).is_empty());
+}
+
+#[rstest]
+#[case("lts", "v0.5.0", false)]
+#[case("v0.5.0", "v0.5.0", false)]
+#[case("latest", "v0.5.7", true)]
+#[case("nightly", "v0.5.7", true)]
+#[case("v0.5.4", "v0.5.4", true)]
+#[case("v9.9.9", "v0.5.7", true)]
+fn firmware_profiles(#[case] version: &str, #[case] tag: &str, #[case] animations: bool) {
+    let firmware = firmware_profile(version);
+    assert_eq!(firmware.version, tag);
+    assert_eq!(firmware.animations, animations);
+    assert_eq!(firmware.triggers, animations);
+}
+
+#[test]
+fn generate_kll_profiles() {
+    let config: KllConfig = {
+        let contents = fs::read_to_string("layouts/K-Type-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let content = |version: &str| {
+        generate_kll(&config, firmware_profile(version), false)
+            .into_iter()
+            .map(|f| f.content)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(content("v0.5.4"), content("latest"));
+    assert_eq!(content("v0.5.0"), content("lts"));
+    assert_ne!(content("lts"), content("latest"));
+
+    let lts = firmware_profile("lts");
+    assert!(!lts.supports_result("#:animation_control( 3 )"));
+    assert!(lts.supports_result("#:ledControl( 0, 15 )"));
+}
+
+#[test]
+fn generate_kll_build_warnings() {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/K-Type-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let (_, warnings) = generate_kll_with_warnings(&config, firmware_profile("latest"), false);
+    assert!(warnings.is_empty());
+
+    let mut triggers = IndexMap::new();
+    triggers.insert(
+        1,
+        Trigger {
+            action: "rainbow".to_string(),
+            label: "rainbow".to_string(),
+            _type: "animation".to_string(),
+        },
+    );
+    config.matrix[0].triggers = Some(triggers);
+    let (_, warnings) = generate_kll_with_warnings(&config, firmware_profile("lts"), false);
+    let message = |prefix: &str| {
+        warnings
+            .iter()
+            .find(|w| w.message.starts_with(prefix))
+            .unwrap_or_else(|| panic!("No warning for {}", prefix))
+    };
+
+    let trigger = message("Trigger A[rainbow]");
+    assert_eq!(trigger.layer, Some(1));
+    assert_eq!(
+        trigger.code.as_deref(),
+        Some(config.matrix[0].code.as_str())
+    );
+    assert!(message("Animation").message.ends_with("was dropped"));
+    assert!(warnings
+        .iter()
+        .any(|w| w.message.contains("was commented out") && w.code.is_some()));
+    assert!(warnings
+        .iter()
+        .any(|w| w.message.contains("was rewritten to ledControl(")));
+}
+
+#[rstest]
+#[case(
+    r#"{"type": "wave", "colors": [[255, 0, 0], [0, 0, 255]], "direction": "right", "steps": 4}"#,
+    4,
+    "P[c:-50%](0,0,255), P[c:0%](255,0,0), P[c:50%](0,0,255), P[c:100%](255,0,0), P[c:150%](0,0,255)"
+)]
+#[case(
+    r#"{"type": "wave", "colors": [[255, 0, 0], [0, 0, 255]], "direction": "up"}"#,
+    50,
+    "P[r:-50%](0,0,255), P[r:0%](255,0,0), P[r:50%](0,0,255), P[r:100%](255,0,0), P[r:150%](0,0,255)"
+)]
+#[case(
+    r#"{"type": "breathe", "color": [0, 200, 100], "steps": 10}"#,
+    10,
+    "P[c:0%](0,0,0), P[c:100%](0,0,0)"
+)]
+#[case(
+    r#"{"type": "solid", "color": [1, 2, 3]}"#,
+    1,
+    "P[c:0%](1,2,3), P[c:100%](1,2,3)"
+)]
+#[case(
+    r#"{"type": "ripple", "color": [255, 255, 255], "steps": 10}"#,
+    10,
+    "P[c:45%](0,0,0), P[c:50%](255,255,255), P[c:50%](255,255,255), P[c:55%](0,0,0)"
+)]
+fn animation_generators(#[case] generator: &str, #[case] len: usize, #[case] first: &str) {
+    let generator: Generator = serde_json::from_str(generator).unwrap();
+    let frames = generate_frames(&generator, &[]);
+    assert_eq!(frames.len(), len);
+    assert_eq!(frames[0], first);
+}
+
+#[rstest]
+#[case(r#"{"type": "wave", "colors": [[255, 0, 0]], "direction": "left", "steps": 4000000000}"#)]
+#[case(r#"{"type": "breathe", "color": [0, 200, 100], "steps": 1001}"#)]
+#[case(r#"{"type": "ripple", "color": [255, 255, 255], "steps": 1001}"#)]
+fn animation_generator_steps(#[case] generator: &str) {
+    assert!(serde_json::from_str::<Generator>(generator).is_err());
+    let generator = generator
+        .replace("4000000000", "1000")
+        .replace("1001", "1000");
+    let generator: Generator = serde_json::from_str(&generator).unwrap();
+    assert_eq!(generate_frames(&generator, &[]).len(), MAX_STEPS);
+}
+
+#[test]
+fn animation_generator_frames() {
+    let generator = Generator::Wave {
+        colors: vec![[255, 0, 0], [0, 255, 0]],
+        direction: Direction::Left,
+        steps: 4,
+    };
+    let frames = generate_frames(&generator, &[]);
+    // Moves a quarter of the board per frame, looping after a full cycle
+    assert_eq!(
+        frames[1],
+        "P[c:-25%](255,0,0), P[c:25%](0,255,0), P[c:75%](255,0,0), P[c:125%](0,255,0)"
+    );
+
+    let breathe = generate_frames(
+        &Generator::Breathe {
+            color: [200, 100, 0],
+            steps: 4,
+        },
+        &[],
+    );
+    assert_eq!(breathe[2], "P[c:0%](200,100,0), P[c:100%](200,100,0)");
+
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/K-Type-NoAnimations.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let animation: Animation = serde_json::from_str(
+        r#"{"settings": "loop", "generator": {"type": "solid", "color": [9, 9, 9]}}"#,
+    )
+    .unwrap();
+    let mut animations = IndexMap::new();
+    animations.insert("gray".to_string(), animation);
+    config.animations = Some(animations);
+    let files = generate_kll(&config, firmware_profile("latest"), false);
+    assert!(files[0]
+        .content
+        .contains("A[gray] <= loop;\nA[gray, 1] <= P[c:0%](9,9,9), P[c:100%](9,9,9);\n"));
+}
+
+#[test]
+fn animation_ripple_key() {
+    let mut config: KllConfig = {
+        let contents = fs::read_to_string("layouts/K-Type-NoAnimations.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let led = |id: usize, scan_code: &str, x: f32| Led {
+        id,
+        scan_code: Some(scan_code.to_string()),
+        x,
+        y: 0.,
+        channels: None,
+    };
+    let leds = vec![
+        led(1, "0x01", 10.),
+        led(2, "0x02", 60.),
+        led(3, "0x03", 110.),
+    ];
+    assert_eq!(led_column(&leds, "0x02"), Some(50.));
+    assert_eq!(led_column(&leds, "0x04"), None);
+    // The leftmost LED, the ripple has to cross the whole board
+    assert_eq!(led_column(&leds, "0x01"), Some(0.));
+
+    let generator: Generator = serde_json::from_str(
+        r#"{"type": "ripple", "color": [255, 255, 255], "speed": 10, "key": "0x01"}"#,
+    )
+    .unwrap();
+    let frames = generate_frames(&generator, &leds);
+    assert_eq!(frames.len(), 10);
+    assert_eq!(
+        frames[0],
+        "P[c:-5%](0,0,0), P[c:0%](255,255,255), P[c:0%](255,255,255), P[c:5%](0,0,0)"
+    );
+    assert!(frames[9].contains("P[c:90%]"));
+
+    let animation = Animation {
+        _type: None,
+        frames: Vec::new(),
+        settings: "replace:clear".to_string(),
+        generator: Some(generator),
+    };
+    let mut animations = IndexMap::new();
+    animations.insert("splash".to_string(), animation);
+    config.animations = Some(animations);
+    let (documents, warnings) = generate_kll_documents(&config, firmware_profile("latest"), false);
+    assert!(warnings.is_empty());
+    assert!(documents[0].to_string().contains("S0x01 :+ A[splash];\n"));
+
+    // Without an LED for the key it starts in the middle
+    let animation = config
+        .animations
+        .as_mut()
+        .unwrap()
+        .get_mut("splash")
+        .unwrap();
+    animation.generator = Some(Generator::Ripple {
+        color: [255, 255, 255],
+        steps: 10,
+        speed: None,
+        key: Some("0xFF".to_string()),
+    });
+    let (documents, warnings) = generate_kll_documents(&config, firmware_profile("latest"), false);
+    assert_eq!(warnings.len(), 1);
+    assert!(documents[0]
+        .to_string()
+        .contains("A[splash, 1] <= P[c:45%](0,0,0), P[c:50%](255,255,255)"));
+}
+
+#[rstest]
+#[case("P[c:0%] (0,255,0)", PixelTarget::Column(0.), [0, 255, 0])]
+#[case("P[r:-24%](1,2,3)", PixelTarget::Row(-24.), [1, 2, 3])]
+#[case("P[5](255)", PixelTarget::Ids(vec![5]), [255, 255, 255])]
+#[case("P[1-3, 7](0,0,9)", PixelTarget::Ids(vec![1, 2, 3, 7]), [0, 0, 9])]
+fn preview_parse_frame(#[case] frame: &str, #[case] target: PixelTarget, #[case] color: [u8; 3]) {
+    let pixels = parse_frame(frame).unwrap();
+    assert_eq!(pixels, vec![(Pixel { target, color }, true)]);
+}
+
+#[test]
+fn preview_animation() {
+    assert!(parse_frame("P[c:0%](0,255)").is_err());
+    assert!(parse_frame("PL[0](0,0,0)").is_err());
+
+    let config: KllConfig = {
+        let contents = fs::read_to_string("layouts/K-Type-Standard.json").unwrap();
+        serde_json::from_str(&contents).unwrap()
+    };
+    let leds = config.leds.clone().unwrap();
+    let (settings, frames) = animation_frames(&config, "rainbow_wave").unwrap();
+    let preview = simulate(&settings, &frames, &leds).unwrap();
+    assert_eq!(preview.frames.len(), frames.len());
+    assert_eq!(preview.leds.len(), leds.len());
+
+    // Interpolated from the first column (0,255,0) to the last one (0,0,255)
+    let first = &preview.frames[0];
+    let min_x = leds.iter().map(|l| l.x).fold(f32::INFINITY, f32::min);
+    let leftmost = leds.iter().position(|l| l.x == min_x).unwrap();
+    assert_eq!(first[leftmost], [0, 255, 0]);
+    let max_x = leds.iter().map(|l| l.x).fold(0., f32::max);
+    let rightmost = leds.iter().position(|l| l.x == max_x).unwrap();
+    assert_eq!(first[rightmost], [0, 0, 255]);
+
+    // Without interpolation only the pixel's LEDs are set, and kept without replace:clear
+    let frames = vec!["P[1](255,0,0)".to_string(), "P[2](0,255,0)".to_string()];
+    let preview = simulate("loop", &frames, &leds).unwrap();
+    assert_eq!(preview.frames[1][0], [255, 0, 0]);
+    assert_eq!(preview.frames[1][1], [0, 255, 0]);
+    assert_eq!(preview.frames[1][2], [0, 0, 0]);
+    let preview = simulate("loop, replace:clear", &frames, &leds).unwrap();
+    assert_eq!(preview.frames[1][0], [0, 0, 0]);
+
+    let gif = render_gif(&config, &preview, 100).unwrap();
+    assert!(gif.starts_with(b"GIF89a"));
+    assert_eq!(gif.last(), Some(&0x3b));
+}
+
+#[test]
+fn preview_oversized_input() {
+    assert!(parse_frame("P[0-4000000000](1)").is_err());
+    assert!(parse_frame(&format!("P[{}](1)", MAX_PIXEL_ID + 1)).is_err());
+    assert!(parse_frame(&format!("P[0-{}](1)", MAX_PIXEL_ID)).is_ok());
+
+    let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+    let mut config: KllConfig = serde_json::from_str(&contents).unwrap();
+    let preview = Preview {
+        leds: vec![],
+        frames: vec![vec![]],
+    };
+    config.matrix[0].x = Some(20000.);
+    assert!(render_gif(&config, &preview, 100).is_err());
+    config.matrix[0].x = Some(1e30);
+    assert!(render_gif(&config, &preview, 100).is_err());
+
+    config.matrix[0].x = Some(0.);
+    assert!(render_gif(&config, &preview, GIF_MAX_DELAY_MS).is_ok());
+    assert!(render_gif(&config, &preview, GIF_MAX_DELAY_MS + 1).is_err());
+
+    // Every frame of about 1000x1000 pixels fits, all of them together don't
+    config.matrix[0].x = Some(240.);
+    config.matrix[0].y = Some(240.);
+    assert!(render_gif(&config, &preview, 100).is_ok());
+    let preview = Preview {
+        leds: vec![],
+        frames: vec![vec![]; 70],
+    };
+    assert!(render_gif(&config, &preview, 100).is_err());
+}
+
+#[rstest]
+#[case("layouts/MD1-Standard.json", "lts")]
+#[case("layouts/K-Type-Standard.json", "latest")]
+#[case("layouts/WhiteFox-TheTrueFox.json", "latest")]
+#[case("layouts/MDErgo1-Default.json", "latest")]
+fn resource_limits_layouts(#[case] layout: &str, #[case] env: &str) {
+    let contents = fs::read_to_string(layout).unwrap();
+    let config: KllConfig = serde_json::from_str(&contents).unwrap();
+    let firmware = firmware_profile(env);
+    let (documents, _) = generate_kll_documents(&config, firmware, false);
+    assert!(check_limits(&config.header.name, firmware, &documents).is_empty());
+    assert!(estimate_flash(&documents) > 0);
+}
+
+#[test]
+fn resource_limits_exceeded() {
+    // K-Type allows 16 layers, the LTS firmware only 8
+    let documents = vec![KllDocument::default(); 10];
+    assert!(check_limits("K-Type", firmware_profile("latest"), &documents).is_empty());
+    let errors = check_limits("K-Type", firmware_profile("lts"), &documents);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].limit, "layers");
+    assert_eq!((errors[0].used, errors[0].max), (10, 8));
+    assert_eq!(
+        errors[0].message,
+        "10 layers used, K-Type on v0.5.0 supports at most 8"
+    );
+
+    let frame = |name: &str, index| Statement::AnimationFrame {
+        name: name.to_string(),
+        index,
+        frame: "P[c:0%](255,0,0), P[c:100%](0,0,255)".to_string(),
+    };
+    let mut frames = (1..=60).map(|i| frame("wave", i)).collect::<Vec<_>>();
+    frames.extend((1..=10).map(|i| frame("blink", i)));
+    let documents = vec![KllDocument {
+        name: "MD1-0.kll".to_string(),
+        sections: vec![frames],
+    }];
+    let errors = check_limits("MD1", firmware_profile("latest"), &documents);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].limit, "frames");
+    assert_eq!(
+        errors[0].message,
+        "70 animation frames used (wave: 60, blink: 10), MD1 on v0.5.7 supports at most 64"
+    );
+
+    let bindings = (0..3000)
+        .map(|i| Statement::Remap {
+            trigger: format!("S{}", i),
+            result: "U\"A\"".to_string(),
+        })
+        .collect::<Vec<_>>();
+    let documents = vec![KllDocument {
+        name: "MD1-0.kll".to_string(),
+        sections: vec![bindings],
+    }];
+    let errors = check_limits("MD1", firmware_profile("latest"), &documents);
+    assert_eq!(errors[0].limit, "flash");
+    assert!(errors[0].used > errors[0].max);
+    // The LTS firmware leaves more flash for the keymap, and MD1.1 has a larger chip
+    assert!(check_limits("MD1", firmware_profile("lts"), &documents).is_empty());
+    assert!(check_limits("MD1.1", firmware_profile("latest"), &documents).is_empty());
+}
+
+#[test]
+fn board_limits_versions() {
+    let limits = |name, env| {
+        let limits = board_limits(name, firmware_profile(env));
+        (limits.flash, limits.max_layers, limits.max_frames)
+    };
+    assert_eq!(limits("MD1", "lts"), (64 * 1024, 8, 0));
+    assert_eq!(limits("MD1", "latest"), (32 * 1024, 8, 64));
+    assert_eq!(limits("K-Type", "v0.5.0"), (160 * 1024, 16, 0));
+    assert_eq!(limits("K-Type", "v0.5.6"), (96 * 1024, 16, 512));
+    assert_eq!(limits("GeminiDuskDawn", "latest"), (256 * 1024, 16, 1024));
+    assert_eq!(limits("ICPad", "latest"), (32 * 1024, 8, 64));
+}
+
+#[rstest]
+#[case("latest", Some("latest"))]
+#[case("v0.5.6", Some("v0.5.6"))]
+#[case("stable", Some("latest"))]
+#[case("beta", Some("nightly"))]
+#[case("v0.5.0", Some("lts"))]
+#[case("v0.5.3", None)]
+#[case("v0.5.5", None)]
+#[case("", None)]
+fn resolve_versions(#[case] env: &str, #[case] name: Option<&str>) {
+    let version = |channel: &str, container: &str, git_tag: &str| VersionInfo {
+        container: container.to_string(),
+        channel: channel.to_string(),
+        git_tag: git_tag.to_string(),
+        info: None,
+        image: ContainerImage {
+            service: container.to_string(),
+            image: format!("kiisrv-{}:latest", container),
+            // Only controller-055 hasn't been built
+            available: container != "controller-055",
+            digest: None,
+            size: None,
+        },
+    };
+    let mut versions = std::collections::HashMap::new();
+    versions.insert(
+        "nightly".to_string(),
+        version("beta", "controller-057", "v0.5.7"),
+    );
+    versions.insert(
+        "latest".to_string(),
+        version("stable", "controller-057", "v0.5.7"),
+    );
+    versions.insert(
+        "lts".to_string(),
+        version("lts", "controller-050", "v0.5.0"),
+    );
+    versions.insert(
+        "v0.5.6".to_string(),
+        version("beta", "controller-056", "v0.5.6"),
+    );
+    versions.insert(
+        "v0.5.5".to_string(),
+        version("stable", "controller-055", "v0.5.5"),
+    );
+    let resolved = resolve_version(&versions, env).map(|(name, _)| name.as_str());
+    assert_eq!(resolved, name);
+}
+
+#[test]
+fn container_image_status() {
+    let config = r#"{
+        "name": "kiisrv",
+        "services": {
+            "controller-050": {"image": "kiisrv-controller-050:latest"},
+            "controller-057": {}
+        }
+    }"#;
+    assert_eq!(
+        compose_images(config),
+        vec![
+            (
+                "controller-050".to_string(),
+                "kiisrv-controller-050:latest".to_string()
+            ),
+            (
+                "controller-057".to_string(),
+                "kiisrv-controller-057".to_string()
+            ),
+        ]
+    );
+    assert!(compose_images("").is_empty());
+
+    assert_eq!(
+        parse_image_inspect("sha256:0123abcd 1073741824\n"),
+        Some(("sha256:0123abcd".to_string(), 1073741824))
+    );
+    assert_eq!(parse_image_inspect(""), None);
+}
+
+#[test]
+fn tag_cache() {
+    let db = Connection::open_in_memory().unwrap();
+    init_config_db(&db).unwrap();
+    assert!(cached_tags(&db).is_empty());
+
+    let info = |commit: u16, hash: &str| ReleaseInfo {
+        commit,
+        date: "2019-06-09 15:53:45 -0700".to_string(),
+        hash: hash.to_string(),
+        bcd: format!("0.{}", commit),
+        notes: "https://github.com/kiibohd/controller/releases/tag/v0.5.7".to_string(),
+    };
+    let mut tags = IndexMap::new();
+    tags.insert("v0.5.0".to_string(), info(10, "aaaa"));
+    tags.insert("v0.5.7".to_string(), info(20, "bbbb"));
+    store_tags(&db, &tags);
+
+    let cached = cached_tags(&db);
+    assert_eq!(cached.len(), 2);
+    assert_eq!(cached["v0.5.7"].commit, 20);
+    assert_eq!(cached["v0.5.7"].hash, "bbbb");
+    assert_eq!(cached["v0.5.0"].bcd, "0.10");
+
+    // A moved tag replaces its entry
+    let mut moved = IndexMap::new();
+    moved.insert("v0.5.7".to_string(), info(21, "cccc"));
+    store_tags(&db, &moved);
+    let cached = cached_tags(&db);
+    assert_eq!(cached.len(), 2);
+    assert_eq!(cached["v0.5.7"].hash, "cccc");
+    assert_eq!(cached["v0.5.0"].hash, "aaaa");
+}
+
+#[test]
+fn admin_token() {
+    assert!(admin_authorized("secret", Some("Bearer secret")));
+    assert!(!admin_authorized("secret", Some("Bearer secreT")));
+    assert!(!admin_authorized("secret", Some("Bearer secret2")));
+    assert!(!admin_authorized("secret", Some("secret")));
+    assert!(!admin_authorized("secret", None));
+    assert!(!admin_authorized("", Some("Bearer ")));
+}
+
+#[test]
+fn admin_update_retire_versions() {
+    let db = Connection::open_in_memory().unwrap();
+    init_config_db(&db).unwrap();
+    let seeded = version_rows(&db);
+    assert!(seeded.iter().any(|v| v.name == "lts"));
+
+    // New versions need every field
+    let partial = VersionUpdate {
+        channel: Some("beta".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(update_version(&db, "v0.5.8", partial).unwrap(), None);
+    let full = VersionUpdate {
+        channel: Some("beta".to_string()),
+        container: Some("controller-058".to_string()),
+        git_tag: Some("v0.5.8".to_string()),
+    };
+    let added = update_version(&db, "v0.5.8", full).unwrap().unwrap();
+    assert_eq!(added.container, "controller-058");
+
+    // Existing versions only change the given fields
+    let rechannel = VersionUpdate {
+        channel: Some("stable".to_string()),
+        ..Default::default()
+    };
+    let updated = update_version(&db, "v0.5.8", rechannel).unwrap().unwrap();
+    assert_eq!(updated.channel, "stable");
+    assert_eq!(updated.git_tag, "v0.5.8");
+    assert_eq!(version_rows(&db).len(), seeded.len() + 1);
+
+    assert!(retire_version(&db, "lts").unwrap());
+    assert!(!retire_version(&db, "lts").unwrap());
+    // Restarting doesn't seed retired versions again
+    init_config_db(&db).unwrap();
+    let rows = version_rows(&db);
+    assert!(!rows.iter().any(|v| v.name == "lts"));
+    assert_eq!(rows.len(), seeded.len());
+}
+
+#[rstest]
+#[case("lts..latest", Some(("lts", "latest")))]
+#[case("v0.5.0..v0.5.7", Some(("v0.5.0", "v0.5.7")))]
+#[case("v0.5.0...v0.5.7", None)]
+#[case("..latest", None)]
+#[case("latest", None)]
+fn changelog_range(#[case] range: &str, #[case] expected: Option<(&str, &str)>) {
+    assert_eq!(split_range(range), expected);
+}
+
+#[test]
+fn changelog_entries() {
+    let out = "0123abcd\t2019-01-02 03:04:05 -0800\tFix LED\tbuffer\n\nbadline\n";
+    assert_eq!(
+        parse_changelog(out),
+        vec![ChangelogEntry {
+            hash: "0123abcd".to_string(),
+            date: "2019-01-02 03:04:05 -0800".to_string(),
+            summary: "Fix LED\tbuffer".to_string(),
+        }]
+    );
+}
+
+#[test]
+fn layout_etag() {
+    let (key, commit) = layout_key("MD1-Standard.json", "HEAD").unwrap();
+    let layout = read_layout(&key).unwrap();
+    assert!(!is_pinned(&commit, "HEAD"));
+
+    let response = layout_response(&layout, false, &HeaderMap::new());
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ETAG], layout.etag.as_str());
+    assert_eq!(
+        response.headers()[header::CACHE_CONTROL],
+        "public, no-cache"
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::IF_NONE_MATCH, layout.etag.parse().unwrap());
+    let response = layout_response(&layout, false, &headers);
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()[header::ETAG], layout.etag.as_str());
+
+    headers.insert(header::IF_NONE_MATCH, "\"0123\"".parse().unwrap());
+    assert_eq!(
+        layout_response(&layout, false, &headers).status(),
+        StatusCode::OK
+    );
+
+    // The same cached layout pinned to its commit can be cached forever, with the same ETag
+    let (pinned_key, _) = layout_key("MD1-Standard.json", &commit).unwrap();
+    assert_eq!(pinned_key, key);
+    assert!(is_pinned(&commit, &commit));
+    let response = layout_response(&layout, true, &HeaderMap::new());
+    assert_eq!(
+        response.headers()[header::CACHE_CONTROL],
+        "public, max-age=31536000, immutable"
+    );
+
+    // The ETag is the blob id, unchanged files keep it across commits
+    let blob = std::process::Command::new("git")
+        .args(["rev-parse", &key])
+        .output()
+        .unwrap();
+    let blob = String::from_utf8_lossy(&blob.stdout).trim().to_string();
+    assert_eq!(layout.etag, format!("\"{}\"", blob));
+    let (key, _) = layout_key("MD1-Standard.json", "HEAD~1").unwrap();
+    assert_eq!(read_layout(&key).unwrap().etag, layout.etag);
+}
+
+#[rstest]
+#[case("MD1-Missing.json", "HEAD")]
+#[case("MD1-Standard.json", "no-such-rev")]
+#[case("../Cargo.toml", "HEAD")]
+#[case("..\\Cargo.toml", "HEAD")]
+fn layout_not_found(#[case] file: &str, #[case] rev: &str) {
+    let layout = layout_key(file, rev).and_then(|(key, _)| read_layout(&key));
+    assert_eq!(layout.err(), Some(StatusCode::NOT_FOUND));
+}
+
+#[rstest]
+#[case("layouts/WhiteFox-TheTrueFox.json")]
+#[case("layouts/WhiteFox-Iso.json")]
+#[case("layouts/WhiteFox-Vanilla.json")]
+#[case("layouts/WhiteFox-Winkeyless.json")]
+#[case("layouts/WhiteFox-Aria.json")]
+#[case("layouts/WhiteFox-JackofAllTrades.json")]
+fn unmatched_keys_lts_base(#[case] layout: &str) {
+    let contents = fs::read_to_string(layout).unwrap();
+    let config: KllConfig = serde_json::from_str(&contents).unwrap();
+    let firmware = firmware_profile("lts");
+    assert!(unmatched_keys(&config, firmware).is_empty());
+
+    // Keys missing from the LTS base are skipped with a warning
+    let (documents, warnings) = generate_kll_documents(&config, firmware, false);
+    assert!(!documents.is_empty());
+    let skipped = warnings
+        .iter()
+        .filter(|w| w.message.ends_with("base layout of v0.5.0 and was skipped"))
+        .map(|w| w.code.as_deref().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(skipped, ["0x07", "0x0A", "0x0C", "0x33"]);
+    assert!(unmatched_keys(&config, firmware_profile("latest")).is_empty());
+}